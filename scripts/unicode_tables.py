@@ -0,0 +1,76 @@
+#!/usr/bin/env python3
+"""Generates src/unicode.rs, the tables used for Unicode normalization (NFC).
+
+The data comes from the unicodedata module of the Python running this script:
+
+    python3 scripts/unicode_tables.py > src/unicode.rs
+"""
+
+import sys
+import unicodedata
+
+# Hangul syllables are decomposed and composed algorithmically
+HANGUL = range(0xAC00, 0xD7A4)
+
+
+def char(c):
+    return "'\\u{%x}'" % ord(c)
+
+
+def string(s):
+    return '"' + "".join("\\u{%x}" % ord(c) for c in s) + '"'
+
+
+def table(name, kind, description, entries, per_line):
+    print()
+    for line in description:
+        print(f"/// {line}")
+    print(f"pub const {name}: &[{kind}] = &[")
+    for i in range(0, len(entries), per_line):
+        print("    " + " ".join(e + "," for e in entries[i:i + per_line]))
+    print("];")
+
+
+def main():
+    chars = [chr(c) for c in range(sys.maxunicode + 1) if not 0xD800 <= c <= 0xDFFF]
+
+    classes = []
+    for c in chars:
+        ccc = unicodedata.combining(c)
+        if ccc == 0:
+            continue
+        if classes and classes[-1][2] == ccc and ord(classes[-1][1]) + 1 == ord(c):
+            classes[-1][1] = c
+        else:
+            classes.append([c, c, ccc])
+
+    decompositions = []
+    compositions = []
+    for c in chars:
+        if ord(c) in HANGUL:
+            continue
+        decomposition = unicodedata.decomposition(c)
+        if not decomposition or decomposition.startswith("<"):
+            continue
+        decompositions.append((c, unicodedata.normalize("NFD", c)))
+
+        # singletons, non-starter decompositions and exclusions do not compose again
+        parts = [chr(int(p, 16)) for p in decomposition.split()]
+        if len(parts) == 2 and unicodedata.normalize("NFC", c) == c:
+            compositions.append((parts[0], parts[1], c))
+    compositions.sort()
+
+    print(f"// Generated by scripts/unicode_tables.py from Unicode {unicodedata.unidata_version}, do not edit.")
+    table("COMBINING_CLASSES", "(char, char, u8)",
+        ["Ranges of characters with a canonical combining class other than 0, sorted."],
+        [f"({char(s)}, {char(e)}, {ccc})" for s, e, ccc in classes], 3)
+    table("DECOMPOSITIONS", "(char, &str)",
+        ["Full canonical decompositions, sorted, except for Hangul syllables."],
+        [f"({char(c)}, {string(d)})" for c, d in decompositions], 3)
+    table("COMPOSITIONS", "(char, char, char)",
+        ["Pairs that compose to a primary composite, sorted, except for Hangul syllables."],
+        [f"({char(a)}, {char(b)}, {char(c)})" for a, b, c in compositions], 3)
+
+
+if __name__ == "__main__":
+    main()
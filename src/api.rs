@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::sync::{MutexGuard, RwLockWriteGuard};
+
+use crate::check;
+use crate::request::{self, Request};
+use crate::server::{self, ReloadStatus, Response, ResponseType, State};
+use crate::stats::json_string;
+use crate::store::{self, Link, Redirect, Store};
+use crate::time::{DateTime, Timestamp};
+use crate::token::Generator;
+
+
+const API_PREFIX: &str = "/_api/";
+const LINKS_PATH: &str = "/_api/links";
+const STATS_PATH: &str = "/_api/stats";
+const STATS_PAGE_PATH: &str = "/_stats";
+const STATUS_PATH: &str = "/_api/status";
+
+
+pub fn handles(path: &str) -> bool {
+    path.starts_with(API_PREFIX) || path == STATS_PAGE_PATH
+}
+
+/// Handles requests for which `handles()` is true.
+///
+/// The API is only available if a secret has been configured, which clients have to send as
+/// bearer token in the `Authorization` header.
+/// The stats page also accepts it as password for basic authentication, so browsers can ask for it.
+pub fn handle(state: &State, request: &Request) -> Response {
+    let secret = match &state.api_secret {
+        Some(secret) => secret,
+        None => return Response::new(ResponseType::NotFound),
+    };
+
+    if !is_authorized(request, secret) {
+        let challenge = if request.path == STATS_PAGE_PATH { "Basic realm=\"lisho\"" } else { "Bearer" };
+        return Response::new(ResponseType::Unauthorized)
+            .with_header("WWW-Authenticate", challenge);
+    }
+
+    if request.path == STATS_PATH || request.path == STATS_PAGE_PATH {
+        return match request.method.as_str() {
+            "GET" | "HEAD" => stats(state, request.path == STATS_PAGE_PATH),
+            _ => Response::new(ResponseType::MethodNotAllowed).with_header("Allow", "GET, HEAD"),
+        };
+    }
+    if request.path == STATUS_PATH {
+        return match request.method.as_str() {
+            "GET" | "HEAD" => status(state),
+            _ => Response::new(ResponseType::MethodNotAllowed).with_header("Allow", "GET, HEAD"),
+        };
+    }
+    let token = match request.path.strip_prefix(LINKS_PATH) {
+        Some("") => None,
+        Some(rest) => match rest.strip_prefix('/') {
+            Some(token) if !token.is_empty() => Some(token),
+            _ => return Response::new(ResponseType::NotFound),
+        },
+        None => return Response::new(ResponseType::NotFound),
+    };
+
+    let params = match &request.body {
+        Some(body) => match std::str::from_utf8(body).ok().and_then(request::parse_query) {
+            Some(params) => params,
+            None => return Response::new(ResponseType::BadRequest),
+        },
+        None => HashMap::new(),
+    };
+
+    match (request.method.as_str(), token) {
+        ("POST", None) => match params.get("token").filter(|t| !t.is_empty()) {
+            Some(token) => set_link(state, token, &params, false),
+            None => generate_link(state, &state.generator, &params),
+        },
+        ("PUT", Some(token)) => set_link(state, token, &params, true),
+        ("DELETE", Some(token)) => remove_link(state, token),
+        ("OPTIONS", None) => Response::new(ResponseType::Ok).with_header("Allow", "POST, OPTIONS").with_content(""),
+        ("OPTIONS", Some(_)) => Response::new(ResponseType::Ok).with_header("Allow", "PUT, DELETE, OPTIONS").with_content(""),
+        (_, None) => Response::new(ResponseType::MethodNotAllowed).with_header("Allow", "POST, OPTIONS"),
+        (_, Some(_)) => Response::new(ResponseType::MethodNotAllowed).with_header("Allow", "PUT, DELETE, OPTIONS"),
+    }
+}
+
+/// Compares the provided secret without bailing out at the first mismatch.
+fn is_authorized(request: &Request, secret: &str) -> bool {
+    let authorization = request.header("authorization").unwrap_or_default();
+    let provided = if let Some(token) = authorization.strip_prefix("Bearer ") {
+        token.trim().as_bytes().to_vec()
+    } else if let Some(credentials) = authorization.strip_prefix("Basic ").and_then(base64_decode) {
+        // the user name is ignored
+        match credentials.iter().position(|&b| b == b':') {
+            Some(i) => credentials[i + 1..].to_vec(),
+            None => return false,
+        }
+    } else {
+        return false;
+    };
+
+    provided.len() == secret.len()
+        && provided.iter().zip(secret.as_bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut decoded = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in encoded.trim().trim_end_matches('=').bytes() {
+        buffer = (buffer << 6) | ALPHABET.iter().position(|&a| a == c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+fn stats(state: &State, html: bool) -> Response {
+    let (store, stats) = match (state.store.read(), state.stats.lock()) {
+        (Ok(store), Ok(stats)) => (store, stats),
+        _ => return Response::new(ResponseType::InternalServerError),
+    };
+
+    if html {
+        Response::new(ResponseType::Ok).with_content(stats.to_html(store.tokens()))
+    } else {
+        Response::new(ResponseType::Ok)
+            .with_header("Content-Type", "application/json")
+            .with_content(stats.to_json(store.tokens()))
+    }
+}
+
+/// Tells whether the mapping file could be reloaded.
+fn status(state: &State) -> Response {
+    let (reloads, store) = match (state.reloads.lock(), state.store.read()) {
+        (Ok(reloads), Ok(store)) => (reloads, store),
+        _ => return Response::new(ResponseType::InternalServerError),
+    };
+
+    let time = |time| json_string(&DateTime::from_system_time(time).rfc3339());
+    let (success_time, success_links) = reloads.last_success;
+    let last_failure = match &reloads.last_failure {
+        Some((failure_time, reason)) => format!("{{\"time\":{},\"error\":{}}}", time(*failure_time), json_string(reason)),
+        None => "null".to_owned(),
+    };
+
+    let json = format!("{{\"file\":{},\"links\":{},\"last_reload\":{{\"time\":{},\"links\":{success_links}}},\"last_failed_reload\":{last_failure}}}\n",
+        json_string(store.file_path()), store.len(), time(success_time));
+    Response::new(ResponseType::Ok)
+        .with_header("Content-Type", "application/json")
+        .with_content(json)
+}
+
+fn set_link(state: &State, token: &str, params: &HashMap<String, String>,
+                replace: bool) -> Response {
+    if !store::is_valid_token(token) || server::is_reserved(token) {
+        return bad_request("Invalid token");
+    }
+    let link = match link_from_params(params) {
+        Ok(link) => link,
+        Err(response) => return response,
+    };
+
+    let (mut reloads, mut store) = match lock_for_edit(state) {
+        Some(locks) => locks,
+        None => return Response::new(ResponseType::InternalServerError),
+    };
+
+    let exists = store.get(token).is_some();
+    if exists && !replace {
+        return Response::new(ResponseType::Conflict)
+            .with_content(format!("Token '{token}' already exists\n"));
+    }
+
+    write_link(&mut store, &mut reloads, token, &link, exists)
+}
+
+fn generate_link(state: &State, generator: &Generator, params: &HashMap<String, String>) -> Response {
+    let link = match link_from_params(params) {
+        Ok(link) => link,
+        Err(response) => return response,
+    };
+
+    // generating under the write lock keeps concurrent requests from picking the same token
+    let (mut reloads, mut store) = match lock_for_edit(state) {
+        Some(locks) => locks,
+        None => return Response::new(ResponseType::InternalServerError),
+    };
+
+    let token = match generator.generate(&store, &link.url) {
+        Some(token) => token,
+        None => return Response::new(ResponseType::InternalServerError)
+            .with_content("Unable to find a free token\n"),
+    };
+
+    // the hash strategy hands out existing tokens for known URLs
+    if store.get(&token).is_some() {
+        let url = &link.url;
+        return Response::new(ResponseType::Ok)
+            .with_header("Location", format!("/{token}"))
+            .with_content(format!("{token} {url}\n"));
+    }
+
+    write_link(&mut store, &mut reloads, &token, &link, false)
+}
+
+fn link_from_params(params: &HashMap<String, String>) -> Result<Link, Response> {
+    let url = match params.get("url") {
+        Some(url) if !url.is_empty() && !url.contains(char::is_whitespace) => url,
+        Some(_) => return Err(bad_request("Invalid URL")),
+        None => return Err(bad_request("Missing parameter 'url'")),
+    };
+
+    let redirect = match params.get("redirect").map(|r| Redirect::from_code(r)) {
+        Some(Some(redirect)) => Some(redirect),
+        Some(None) => return Err(bad_request("Redirect status must be one of 301, 302, 303, 307 or 308")),
+        None => None,
+    };
+    let forward = match params.get("forward").map(String::as_str) {
+        Some("true") => true,
+        Some("false") | None => false,
+        Some(_) => return Err(bad_request("Forward must be true or false")),
+    };
+    let default = match params.get("default") {
+        Some(default) if default.is_empty() || default.contains(char::is_whitespace) => {
+            return Err(bad_request("Invalid default URL"));
+        },
+        default => default.cloned(),
+    };
+
+    let timestamp = |name| match params.get(name).map(|t| Timestamp::parse(t)) {
+        Some(Some(timestamp)) => Ok(Some(timestamp)),
+        Some(None) => Err(bad_request(&format!("Invalid {name}, expected a time like 2024-05-17 or 2024-05-17T13:00"))),
+        None => Ok(None),
+    };
+    let expires = timestamp("expires")?;
+    let not_before = timestamp("not-before")?;
+
+    let link = Link { url: url.to_owned(), redirect, forward, default, expires, not_before };
+    // links `lisho check` would report are not written in the first place
+    match check::url_errors(&link).first() {
+        Some(e) => Err(bad_request(e)),
+        None => Ok(link),
+    }
+}
+
+/// Locks the reload status and the store in the same order as `State::reload_store`,
+/// as edits reload the store as well.
+fn lock_for_edit(state: &State) -> Option<(MutexGuard<'_, ReloadStatus>, RwLockWriteGuard<'_, Store>)> {
+    let reloads = state.reloads.lock().ok()?;
+    let store = state.store.write().ok()?;
+    Some((reloads, store))
+}
+
+fn write_link(store: &mut Store, reloads: &mut ReloadStatus, token: &str, link: &Link, exists: bool) -> Response {
+    if let Err(e) = store.set(token, link) {
+        eprintln!("Unable to update mapping file: {e}");
+        return Response::new(ResponseType::InternalServerError);
+    }
+    reloads.succeeded(store.len());
+    let url = &link.url;
+    println!("Link set: {token} -> {url}");
+
+    let response_type = if exists { ResponseType::Ok } else { ResponseType::Created };
+    Response::new(response_type)
+        .with_header("Location", format!("/{token}"))
+        .with_content(format!("{token} {url}\n"))
+}
+
+fn remove_link(state: &State, token: &str) -> Response {
+    let (mut reloads, mut store) = match lock_for_edit(state) {
+        Some(locks) => locks,
+        None => return Response::new(ResponseType::InternalServerError),
+    };
+
+    match store.remove(token) {
+        Ok(true) => {
+            reloads.succeeded(store.len());
+            println!("Link removed: {token}");
+            Response::new(ResponseType::Ok).with_content(format!("Removed '{token}'\n"))
+        },
+        Ok(false) => Response::new(ResponseType::NotFound),
+        Err(e) => {
+            eprintln!("Unable to update mapping file: {e}");
+            Response::new(ResponseType::InternalServerError)
+        },
+    }
+}
+
+fn bad_request(message: &str) -> Response {
+    Response::new(ResponseType::BadRequest).with_content(format!("{message}\n"))
+}
@@ -0,0 +1,218 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::SystemTime;
+
+use crate::api;
+use crate::normalize::Normalization;
+use crate::request::percent_decode;
+use crate::server;
+use crate::settings::Settings;
+use crate::store::{Definition, Files, Link};
+
+
+/// A problem found in a mapping file.
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// The parts of an absolute http(s) URL needed to tell where it points.
+struct Url<'a> {
+    host: String,
+    port: u16,
+    path: &'a str,
+}
+
+/// Addresses under which this instance can be reached.
+struct Instance {
+    hostnames: Vec<String>,
+    local_hosts: Vec<String>,
+    port: Option<u16>,
+}
+
+
+const LOCAL_HOSTS: &[&str] = &["localhost", "127.0.0.1", "[::1]", "0.0.0.0", "[::]"];
+
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => f.write_str("error"),
+            Severity::Warning => f.write_str("warning"),
+        }
+    }
+}
+
+impl Instance {
+    fn new(settings: &Settings) -> Self {
+        let addr = settings.addr.to_lowercase();
+        let (host, port) = split_host_port(&addr);
+        let mut local_hosts = vec![host.to_owned()];
+        if LOCAL_HOSTS.contains(&host) {
+            local_hosts.extend(LOCAL_HOSTS.iter().map(|h| h.to_string()));
+        }
+
+        Instance {
+            hostnames: settings.hostnames.iter().map(|h| h.to_lowercase()).collect(),
+            local_hosts,
+            port: port.and_then(|p| p.parse().ok()),
+        }
+    }
+
+    /// Public host names match on any port, as there usually is a proxy in front.
+    fn serves(&self, url: &Url) -> bool {
+        self.hostnames.contains(&url.host)
+            || (self.local_hosts.contains(&url.host) && self.port == Some(url.port))
+    }
+}
+
+
+/// Looks for everything in `files` that is likely not what its author intended.
+pub fn check(files: &Files, settings: &Settings) -> Vec<Diagnostic> {
+    let instance = Instance::new(settings);
+    let now = SystemTime::now();
+    // the index of the source comes first, to sort by it later
+    let mut diagnostics = Vec::new();
+    let mut report = |source: usize, line, severity, message| diagnostics.push((source, Diagnostic {
+        file: files.sources[source].path.display().to_string(),
+        line,
+        severity,
+        message,
+    }));
+
+    for (i, source) in files.sources.iter().enumerate() {
+        for (line, error) in source.document.errors() {
+            report(i, line, Severity::Error, error);
+        }
+    }
+    for (source, line, error) in &files.errors {
+        report(*source, *line, Severity::Error, error.clone());
+    }
+
+    // the effective link for every token, the last definition wins
+    let mut links: HashMap<&str, &Definition> = HashMap::new();
+    for definition in &files.definitions {
+        let (source, line, token) = (definition.source, definition.line, definition.token.as_str());
+
+        match links.insert(token, definition) {
+            Some(previous) if previous.source == source => report(source, line, Severity::Error,
+                format!("Duplicate token '{token}', replaces the link on line {}", previous.line)),
+            Some(previous) => report(source, line, Severity::Warning,
+                format!("Token '{token}' replaces the link from {}:{}",
+                    files.sources[previous.source].path.display(), previous.line)),
+            None => (),
+        }
+        if api::handles(&format!("/{token}")) {
+            report(source, line, Severity::Error, format!("Token '{token}' is hidden by the HTTP API"));
+        } else if server::is_builtin(token) {
+            report(source, line, Severity::Warning, format!("Token '{token}' replaces a built-in page"));
+        }
+
+        let link = &definition.link;
+        for e in url_errors(link) {
+            report(source, line, Severity::Error, e);
+        }
+        if link.default.is_some() && !link.is_template() {
+            report(source, line, Severity::Warning, "The default URL is only used by links with placeholders".to_owned());
+        }
+        match (&link.not_before, &link.expires) {
+            (Some(not_before), Some(expires)) if not_before.time >= expires.time => report(source, line, Severity::Error,
+                format!("The link expires at {} before it becomes active at {}", expires.text, not_before.text)),
+            (_, Some(expires)) if link.is_expired(now) => report(source, line, Severity::Warning,
+                format!("The link expired at {}", expires.text)),
+            _ => (),
+        }
+        if link.forward && link.is_template() {
+            report(source, line, Severity::Warning, "Links with placeholders do not forward the rest of the path".to_owned());
+        }
+    }
+
+    for (&token, definition) in &links {
+        if let Some(chain) = find_loop(token, &links, &instance, settings.normalization) {
+            let chain: Vec<_> = chain.iter().map(|t| format!("/{t}")).collect();
+            report(definition.source, definition.line, Severity::Error, format!("Redirect loop {}", chain.join(" -> ")));
+        }
+    }
+
+    diagnostics.sort_by_key(|(source, d)| (*source, d.line));
+    diagnostics.into_iter().map(|(_, d)| d).collect()
+}
+
+/// Follows links pointing back to this instance, returning the tokens visited if they lead
+/// back to `start`.
+fn find_loop(start: &str, links: &HashMap<&str, &Definition>, instance: &Instance,
+             normalization: Normalization) -> Option<Vec<String>> {
+    let mut chain = vec![start.to_owned()];
+    let mut token = start.to_owned();
+
+    loop {
+        let definition = links.get(token.as_str())?;
+        let url = parse_url(&definition.link.url).ok().filter(|u| instance.serves(u))?;
+        token = normalization.apply(&percent_decode(url.path.strip_prefix('/').unwrap_or(url.path), false)?);
+
+        let seen = chain.contains(&token);
+        chain.push(token.clone());
+        if token == start {
+            return Some(chain);
+        } else if seen {
+            // a loop that `start` only leads into is reported for its own links
+            return None;
+        }
+    }
+}
+
+/// Problems with the URL and default URL of `link`, which have to be absolute http(s) URLs.
+pub fn url_errors(link: &Link) -> Vec<String> {
+    // placeholders are checked with an argument filled in, not replaced by the default
+    let filled = Link { default: None, ..link.clone() }.fill(&["x".to_owned()]);
+    let url = parse_url(&filled).err().map(|e| format!("{e} '{}'", link.url));
+    let default = link.default.as_deref()
+        .and_then(|default| parse_url(default).err().map(|e| format!("{e} '{default}'")));
+    url.into_iter().chain(default).collect()
+}
+
+fn parse_url(url: &str) -> Result<Url<'_>, &'static str> {
+    let (scheme, rest) = url.split_once(':').ok_or("Invalid URL")?;
+    let default_port = match scheme.to_lowercase().as_str() {
+        "http" => 80,
+        "https" => 443,
+        _ => return Err("Not an http(s) URL"),
+    };
+    let rest = rest.strip_prefix("//").ok_or("Invalid URL")?;
+
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, rest) = rest.split_at(authority_end);
+    let path = &rest[..rest.find(['?', '#']).unwrap_or(rest.len())];
+
+    let host_port = authority.rsplit_once('@').map_or(authority, |(_, h)| h);
+    let (host, port) = split_host_port(host_port);
+    let valid_host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(ip) => !ip.is_empty() && ip.chars().all(|c| c.is_ascii_hexdigit() || c == ':' || c == '.'),
+        None => !host.is_empty() && host.chars().all(|c| c.is_alphanumeric() || "-._".contains(c)),
+    };
+    if !valid_host {
+        return Err("Invalid host in URL");
+    }
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| "Invalid port in URL")?,
+        None => default_port,
+    };
+
+    Ok(Url { host: host.to_lowercase(), port, path })
+}
+
+/// Splits `host:port`, keeping brackets around IPv6 addresses.
+fn split_host_port(s: &str) -> (&str, Option<&str>) {
+    match s.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') && (!host.contains(':') || host.ends_with(']')) => (host, Some(port)),
+        _ => (s, None),
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::HashSet;
+use std::env;
+
+use crate::check::{self, Severity};
+use crate::server::Server;
+use crate::settings::{self, Assignment, Settings, SETTINGS};
+use crate::signal;
+use crate::store::{self, Link, Store};
+
+
+struct Command {
+    name: &'static str,
+    args: &'static str,
+    help: &'static str,
+}
+
+/// Result of parsing the arguments of a command.
+enum Parsed {
+    Run(Arguments),
+    Help,
+    Version,
+}
+
+struct Arguments {
+    positional: Vec<String>,
+    assignments: Vec<Assignment>,
+    config: Option<String>,
+}
+
+
+const COMMANDS: &[Command] = &[
+    Command { name: "serve", args: "<mapping_file> [address]", help: "serve the links from the mapping file (default)" },
+    Command { name: "add", args: "<mapping_file> [token] <url>", help: "add a link, generating a token if none is given" },
+    Command { name: "remove", args: "<mapping_file> <token>", help: "remove a link" },
+    Command { name: "list", args: "<mapping_file>", help: "list all links" },
+    Command { name: "get", args: "<mapping_file> <token>", help: "print the URL a token points to" },
+    Command { name: "check", args: "<mapping_file>", help: "check the mapping file for errors" },
+    Command { name: "config", args: "check [config_file]", help: "check the config file for errors" },
+];
+
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+
+/// Runs the command given on the command line and returns the exit code.
+pub fn run(args: Vec<String>) -> i32 {
+    let (command, args) = match args.first() {
+        Some(first) if COMMANDS.iter().any(|c| c.name == first) => (first.as_str(), &args[1..]),
+        _ => ("serve", &args[..]),
+    };
+
+    let arguments = match parse_args(args) {
+        Ok(Parsed::Run(arguments)) => arguments,
+        Ok(Parsed::Help) => {
+            print_help();
+            return 0;
+        },
+        Ok(Parsed::Version) => {
+            println!("lisho {}", env!("CARGO_PKG_VERSION"));
+            return 0;
+        },
+        Err(e) => return usage_error(&e),
+    };
+    let positional = &arguments.positional;
+
+    if command == "config" {
+        return config(positional, arguments.config.as_deref());
+    }
+
+    let settings = match load_settings(&arguments) {
+        Ok(settings) => settings,
+        Err(e) => { eprintln!("{e}"); return EXIT_FAILURE; },
+    };
+
+    // the mapping file is only an argument if it is not set otherwise
+    let (file, positional) = match (&settings.mappings, positional.split_first()) {
+        (Some(file), _) => (file.as_str(), &positional[..]),
+        (None, Some((file, rest))) => (file.as_str(), rest),
+        (None, None) => return usage_error(&format!("{command} expects a mapping file")),
+    };
+
+    match command {
+        "serve" => serve(&settings, file, positional),
+        "add" => add(&settings, file, positional, &arguments.assignments),
+        "remove" => remove(&settings, file, positional),
+        "list" => list(&settings, file, positional),
+        "get" => get(&settings, file, positional),
+        "check" => check(&settings, file, positional),
+        _ => unreachable!("unknown command {command}"),
+    }
+}
+
+/// Combines the defaults, the config file, the environment and the command line,
+/// later ones taking precedence.
+fn load_settings(arguments: &Arguments) -> Result<Settings, String> {
+    let mut settings = Settings::default();
+    if let Some(path) = config_path(arguments.config.as_deref()) {
+        settings.apply(&settings::read_config(&path)?)?;
+    }
+    settings.apply(&settings::read_env())?;
+    settings.apply(&arguments.assignments)?;
+    Ok(settings)
+}
+
+fn parse_args(args: &[String]) -> Result<Parsed, String> {
+    let mut arguments = Arguments { positional: Vec::new(), assignments: Vec::new(), config: None };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (name, inline_value) = match arg.as_str() {
+            "-h" | "--help" => return Ok(Parsed::Help),
+            "-V" | "--version" => return Ok(Parsed::Version),
+            "--" => {
+                arguments.positional.extend(args.cloned());
+                break;
+            },
+            long if long.starts_with("--") => match long[2..].split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (&long[2..], None),
+            },
+            "-c" => ("config", None),
+            short if short.starts_with('-') && short.len() == 2 => {
+                let c = short.chars().nth(1);
+                let setting = SETTINGS.iter().find(|s| s.short.is_some() && s.short == c)
+                    .ok_or_else(|| format!("Unknown option '{short}'"))?;
+                (setting.name, None)
+            },
+            _ => {
+                arguments.positional.push(arg.clone());
+                continue;
+            },
+        };
+
+        let value_name = match SETTINGS.iter().find(|s| s.name == name) {
+            Some(setting) => setting.value,
+            None if name == "config" => "<path>",
+            None => return Err(format!("Unknown option '{arg}'")),
+        };
+        let value = match inline_value {
+            Some(value) => value,
+            None => args.next().ok_or_else(|| format!("Missing value {value_name} for --{name}"))?,
+        };
+
+        if name == "config" {
+            arguments.config = Some(value.to_owned());
+        } else {
+            arguments.assignments.push(Assignment {
+                name: name.to_owned(),
+                value: value.to_owned(),
+                origin: format!("--{name}"),
+            });
+        }
+    }
+
+    Ok(Parsed::Run(arguments))
+}
+
+fn serve(settings: &Settings, file: &str, positional: &[String]) -> i32 {
+    let addr = match positional {
+        [] => settings.addr.as_str(),
+        // the address used to be a positional argument only
+        [addr] => addr.as_str(),
+        _ => return usage_error("serve expects a mapping file and optionally an address"),
+    };
+
+    if let Err(e) = settings.check() {
+        eprintln!("{e}");
+        return EXIT_FAILURE;
+    }
+
+    signal::install();
+    let options = match settings.server_options() {
+        Ok(options) => options,
+        Err(e) => { eprintln!("{e}"); return EXIT_FAILURE; },
+    };
+
+    let store = match Store::new(file, &settings.includes, settings.normalization) {
+        Ok(store) => store,
+        Err(e) => { eprintln!("Unable to create store: {}", e); return EXIT_FAILURE; },
+    };
+    let nlinks = store.len();
+
+    let srv = match Server::init(addr, store, options) {
+        Ok(store) => store,
+        Err(e) => { eprintln!("Unable to start server: {}", e); return EXIT_FAILURE; },
+    };
+
+    let nworkers = srv.workers();
+    let scheme = if settings.tls_enabled() { "https" } else { "http" };
+    println!("Listening on {scheme}://{addr} ({nlinks} links, {nworkers} workers)");
+    srv.run();
+    0
+}
+
+fn add(settings: &Settings, file: &str, positional: &[String], assignments: &[Assignment]) -> i32 {
+    let (token, url) = match positional {
+        [url] => (None, url),
+        [token, url] => (Some(token.as_str()), url),
+        _ => return usage_error("add expects a mapping file, optionally a token and a URL"),
+    };
+    if url.contains(char::is_whitespace) {
+        eprintln!("Invalid URL '{url}'");
+        return EXIT_FAILURE;
+    }
+    if let Some(e) = check::url_errors(&store::parse_link(url, &[]).0).first() {
+        eprintln!("{e}");
+        return EXIT_FAILURE;
+    }
+
+    let mut store = match Store::new(file, &settings.includes, settings.normalization) {
+        Ok(store) => store,
+        Err(e) => { eprintln!("Unable to open {file}: {e}"); return EXIT_FAILURE; },
+    };
+
+    let token = match token {
+        Some(token) if !store::is_valid_token(token) || crate::server::is_reserved(token) => {
+            eprintln!("Invalid token '{token}'");
+            return EXIT_FAILURE;
+        },
+        Some(token) if store.get(token).is_some() => {
+            eprintln!("Token '{token}' already exists");
+            return EXIT_FAILURE;
+        },
+        Some(token) => token.to_owned(),
+        None => match settings.generator().generate(&store, url) {
+            Some(token) if store.get(&token).is_some() => {
+                println!("{token}");
+                return 0;
+            },
+            Some(token) => token,
+            None => { eprintln!("Unable to find a free token"); return EXIT_FAILURE; },
+        },
+    };
+
+    // the redirect status is only stored with the link if asked for
+    let redirect = assignments.iter().any(|a| a.name == "redirect").then_some(settings.redirect);
+    let link = Link { redirect, ..store::parse_link(url, &[]).0 };
+    if let Err(e) = store.set(&token, &link) {
+        eprintln!("Unable to update {file}: {e}");
+        return EXIT_FAILURE;
+    }
+
+    println!("{token}");
+    0
+}
+
+fn remove(settings: &Settings, file: &str, positional: &[String]) -> i32 {
+    let token = match positional {
+        [token] => token,
+        _ => return usage_error("remove expects a mapping file and a token"),
+    };
+
+    let mut store = match Store::new(file, &settings.includes, settings.normalization) {
+        Ok(store) => store,
+        Err(e) => { eprintln!("Unable to open {file}: {e}"); return EXIT_FAILURE; },
+    };
+
+    match store.remove(token) {
+        Ok(true) => 0,
+        Ok(false) => { eprintln!("Unknown token '{token}'"); EXIT_FAILURE },
+        Err(e) => { eprintln!("Unable to update {file}: {e}"); EXIT_FAILURE },
+    }
+}
+
+fn list(settings: &Settings, file: &str, positional: &[String]) -> i32 {
+    if !positional.is_empty() {
+        return usage_error("list expects a mapping file");
+    }
+
+    let store = match Store::new(file, &settings.includes, settings.normalization) {
+        Ok(store) => store,
+        Err(e) => { eprintln!("Unable to open {file}: {e}"); return EXIT_FAILURE; },
+    };
+
+    let mut links: Vec<_> = store.iter().collect();
+    links.sort_by_key(|(token, _)| *token);
+    let width = links.iter().map(|(token, _)| token.len() + 1).max().unwrap_or(0);
+    for (token, link) in links {
+        let path = format!("/{token}");
+        let notes: Vec<_> = link.redirect.map(|r| r.code().to_owned()).into_iter()
+            .chain(link.forward.then(|| "forward".to_owned()))
+            .chain(link.not_before.as_ref().map(|t| format!("from {}", t.text)))
+            .chain(link.expires.as_ref().map(|t| format!("until {}", t.text)))
+            .collect();
+        if notes.is_empty() {
+            println!("{path:width$}  {}", link.url);
+        } else {
+            println!("{path:width$}  {} ({})", link.url, notes.join(", "));
+        }
+    }
+
+    0
+}
+
+fn get(settings: &Settings, file: &str, positional: &[String]) -> i32 {
+    let token = match positional {
+        [token] => token,
+        _ => return usage_error("get expects a mapping file and a token"),
+    };
+
+    let store = match Store::new(file, &settings.includes, settings.normalization) {
+        Ok(store) => store,
+        Err(e) => { eprintln!("Unable to open {file}: {e}"); return EXIT_FAILURE; },
+    };
+
+    match store.get(token) {
+        Some(link) => { println!("{}", link.url); 0 },
+        None => { eprintln!("Unknown token '{token}'"); EXIT_FAILURE },
+    }
+}
+
+fn check(settings: &Settings, file: &str, positional: &[String]) -> i32 {
+    if !positional.is_empty() {
+        return usage_error("check expects a mapping file");
+    }
+
+    let files = match store::Files::read(file, &settings.includes, settings.normalization) {
+        Ok(files) => files,
+        Err(e) => { eprintln!("Unable to read {file}: {e}"); return EXIT_FAILURE; },
+    };
+
+    let diagnostics = check::check(&files, settings);
+    for diagnostic in &diagnostics {
+        println!("{}:{}: {}: {}", diagnostic.file, diagnostic.line, diagnostic.severity, diagnostic.message);
+    }
+
+    let errors = diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+    let warnings = diagnostics.len() - errors;
+    let nlinks = files.definitions.iter().map(|d| &d.token).collect::<HashSet<_>>().len();
+    let nfiles = files.sources.len();
+    println!("{file}: {nlinks} links in {nfiles} files, {errors} errors, {warnings} warnings");
+    if errors == 0 { 0 } else { EXIT_FAILURE }
+}
+
+fn config(positional: &[String], config: Option<&str>) -> i32 {
+    let path = match (positional, config_path(config)) {
+        ([check], Some(path)) if check == "check" => path,
+        ([check, path], _) if check == "check" => path.clone(),
+        _ => return usage_error("config expects 'check' and a config file"),
+    };
+
+    let mut settings = Settings::default();
+    let result = settings::read_config(&path)
+        .and_then(|assignments| settings.apply(&assignments))
+        .and_then(|_| settings.check());
+    match result {
+        Ok(()) => { println!("{path}: ok"); 0 },
+        Err(e) => { eprintln!("{e}"); EXIT_FAILURE },
+    }
+}
+
+fn config_path(config: Option<&str>) -> Option<String> {
+    config.map(str::to_owned)
+        .or_else(|| env::var("LISHO_CONFIG").ok().filter(|p| !p.is_empty()))
+}
+
+fn usage_error(message: &str) -> i32 {
+    let bin_name = bin_name();
+    eprintln!("{message}");
+    eprintln!("Try '{bin_name} --help' for more information.");
+    EXIT_USAGE
+}
+
+fn print_help() {
+    let bin_name = bin_name();
+    println!("Usage: {bin_name} [command] [options] <mapping_file> [arguments]");
+    println!();
+    println!("Commands:");
+    let width = COMMANDS.iter().map(|c| c.name.len() + c.args.len() + 1).max().unwrap_or(0);
+    for command in COMMANDS {
+        let usage = format!("{} {}", command.name, command.args);
+        println!("  {usage:width$}   {}", command.help);
+    }
+    println!();
+    println!("Options:");
+    for setting in SETTINGS {
+        let short = setting.short.map(|c| format!("-{c},")).unwrap_or_default();
+        println!("  {short:3} --{} {}", setting.name, setting.value);
+        println!("          {}", setting.help);
+    }
+    println!("  -c, --config <path>");
+    println!("          read settings from a config file, options given here take precedence");
+    println!();
+    println!("  -h, --help");
+    println!("          print this help");
+    println!("  -V, --version");
+    println!("          print the version");
+    println!();
+    println!("Every option can also be set with an environment variable, e.g. LISHO_TOKEN_LENGTH");
+    println!("for --token-length, which takes precedence over the config file (LISHO_CONFIG).");
+    println!("With --mappings (LISHO_MAPPINGS) the mapping file is left out of the arguments.");
+}
+
+fn bin_name() -> String {
+    env::args().next().unwrap_or_else(|| "lisho".to_owned())
+}
@@ -0,0 +1,142 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::prelude::*;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::signal;
+use crate::stats::json_string;
+use crate::time::DateTime;
+
+
+/// Access log with one line per answered request.
+pub struct AccessLog {
+    path: String,
+    format: Format,
+    output: Mutex<Output>,
+}
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Combined,
+    Json,
+}
+
+pub struct Entry<'a> {
+    pub time: SystemTime,
+    pub client: Option<IpAddr>,
+    pub method: Option<&'a str>,
+    pub target: Option<&'a str>,
+    pub version: Option<&'a str>,
+    pub status: u16,
+    pub size: usize,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+struct Output {
+    writer: Box<dyn Write + Send>,
+    hangups: usize,
+}
+
+
+impl AccessLog {
+    /// Opens the log for appending, `-` logs to stdout.
+    pub fn open(path: &str, format: Format) -> io::Result<Self> {
+        Ok(AccessLog {
+            path: path.to_owned(),
+            format,
+            output: Mutex::new(Output {
+                writer: Self::open_writer(path)?,
+                hangups: signal::hangups(),
+            }),
+        })
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let line = match self.format {
+            Format::Combined => entry.combined(),
+            Format::Json => entry.json(),
+        };
+
+        let mut output = match self.output.lock() {
+            Ok(output) => output,
+            Err(_) => return,
+        };
+
+        // logrotate moves the file away and sends SIGHUP to get a new one
+        let hangups = signal::hangups();
+        if output.hangups != hangups {
+            output.hangups = hangups;
+            match Self::open_writer(&self.path) {
+                Ok(writer) => output.writer = writer,
+                Err(e) => eprintln!("Unable to reopen access log: {e}"),
+            }
+        }
+
+        if let Err(e) = writeln!(output.writer, "{line}") {
+            eprintln!("Unable to write access log: {e}");
+        }
+    }
+
+    fn open_writer(path: &str) -> io::Result<Box<dyn Write + Send>> {
+        if path == "-" {
+            Ok(Box::new(io::stdout()))
+        } else {
+            Ok(Box::new(OpenOptions::new().create(true).append(true).open(path)?))
+        }
+    }
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "combined" => Some(Format::Combined),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+impl Entry<'_> {
+    /// The Combined Log Format known from Apache and nginx.
+    fn combined(&self) -> String {
+        let client = self.client.map(|c| c.to_string()).unwrap_or_else(|| "-".to_owned());
+        let time = DateTime::from_system_time(self.time).clf();
+        let request = match (self.method, self.target, self.version) {
+            (Some(method), Some(target), Some(version)) => format!("{method} {target} {version}"),
+            _ => "-".to_owned(),
+        };
+        let size = if self.size == 0 { "-".to_owned() } else { self.size.to_string() };
+
+        format!("{client} - - [{time}] \"{}\" {} {size} \"{}\" \"{}\"",
+            clf_escape(&request), self.status,
+            clf_escape(self.referer.unwrap_or("-")), clf_escape(self.user_agent.unwrap_or("-")))
+    }
+
+    fn json(&self) -> String {
+        let optional = |value: Option<&str>| value.map(json_string).unwrap_or_else(|| "null".to_owned());
+        let client = self.client.map(|c| json_string(&c.to_string())).unwrap_or_else(|| "null".to_owned());
+
+        format!("{{\"time\":\"{}\",\"client\":{client},\"method\":{},\"path\":{},\"status\":{},\"size\":{},\"referer\":{},\"user_agent\":{}}}",
+            DateTime::from_system_time(self.time).rfc3339(),
+            optional(self.method), optional(self.target), self.status, self.size,
+            optional(self.referer), optional(self.user_agent))
+    }
+}
+
+
+/// Keeps quotes and control characters sent by clients from breaking up log lines.
+fn clf_escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,209 @@
+use crate::unicode::{COMBINING_CLASSES, COMPOSITIONS, DECOMPOSITIONS};
+
+
+/// How tokens are normalized before they are compared, both in requests and mapping files.
+#[derive(Clone, Copy, Default)]
+pub struct Normalization {
+    /// `/gh/` is the same as `/gh`.
+    pub trailing_slash: bool,
+    /// `/GH` is the same as `/gh`.
+    pub case: bool,
+    /// Tokens are compared in Unicode Normalization Form C, so `é` written as `e` and a
+    /// combining accent is the same as the single character `é`.
+    pub nfc: bool,
+}
+
+
+/// Hangul syllables are composed of leading consonants, vowels and optional trailing consonants,
+/// see section 3.12 of the Unicode standard.
+const HANGUL_SYLLABLES: u32 = 0xAC00;
+const HANGUL_LEADING: u32 = 0x1100;
+const HANGUL_VOWELS: u32 = 0x1161;
+const HANGUL_TRAILING: u32 = 0x11A7;
+const HANGUL_VOWEL_COUNT: u32 = 21;
+const HANGUL_TRAILING_COUNT: u32 = 28;
+const HANGUL_SYLLABLE_COUNT: u32 = 19 * HANGUL_VOWEL_COUNT * HANGUL_TRAILING_COUNT;
+
+
+impl Normalization {
+    /// Parses a comma separated list of `trailing-slash`, `case` and `nfc`, or `none`.
+    pub fn from_names(names: &str) -> Option<Self> {
+        let mut normalization = Normalization::default();
+        for name in names.split(',').map(str::trim) {
+            match name {
+                "trailing-slash" => normalization.trailing_slash = true,
+                "case" => normalization.case = true,
+                "nfc" => normalization.nfc = true,
+                "none" => (),
+                _ => return None,
+            }
+        }
+        Some(normalization)
+    }
+
+    /// `token` in the form used to look it up.
+    pub fn apply(&self, token: &str) -> String {
+        let mut token = if self.case { token.to_lowercase() } else { token.to_owned() };
+        if self.nfc {
+            token = nfc(&token);
+        }
+        if self.trailing_slash {
+            token.truncate(token.trim_end_matches('/').len());
+        }
+        token
+    }
+}
+
+
+/// Converts `s` to Unicode Normalization Form C.
+///
+/// Characters are decomposed, combining marks put in canonical order and the result composed
+/// again, as described in section 3.11 of the Unicode standard.
+fn nfc(s: &str) -> String {
+    // ASCII is not changed by any normalization
+    if s.is_ascii() {
+        return s.to_owned();
+    }
+
+    let mut chars: Vec<(char, u8)> = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        decompose(c, &mut |c| chars.push((c, combining_class(c))));
+    }
+
+    // sort runs of combining marks by their class, keeping the order of equal ones
+    let mut start = 0;
+    while start < chars.len() {
+        let len = chars[start..].iter().take_while(|(_, class)| *class != 0).count();
+        chars[start..start + len].sort_by_key(|&(_, class)| class);
+        start += len.max(1);
+    }
+
+    let mut composed: Vec<(char, u8)> = Vec::with_capacity(chars.len());
+    let mut starter: Option<usize> = None;
+    for (c, class) in chars {
+        if let Some(starter) = starter {
+            // a mark is blocked from the starter by a mark of the same or a higher class in between
+            let blocked = match composed[starter + 1..].last() {
+                Some(&(_, last_class)) => last_class == 0 || last_class >= class,
+                None => false,
+            };
+            if let Some(primary) = compose(composed[starter].0, c).filter(|_| !blocked) {
+                composed[starter].0 = primary;
+                continue;
+            }
+        }
+        if class == 0 {
+            starter = Some(composed.len());
+        }
+        composed.push((c, class));
+    }
+
+    composed.into_iter().map(|(c, _)| c).collect()
+}
+
+fn decompose(c: char, emit: &mut impl FnMut(char)) {
+    let syllable = (c as u32).wrapping_sub(HANGUL_SYLLABLES);
+    if syllable < HANGUL_SYLLABLE_COUNT {
+        let (vowels_and_trailing, trailing) = (syllable / HANGUL_TRAILING_COUNT, syllable % HANGUL_TRAILING_COUNT);
+        let leading = vowels_and_trailing / HANGUL_VOWEL_COUNT;
+        let vowel = vowels_and_trailing % HANGUL_VOWEL_COUNT;
+        emit(hangul(HANGUL_LEADING + leading));
+        emit(hangul(HANGUL_VOWELS + vowel));
+        if trailing != 0 {
+            emit(hangul(HANGUL_TRAILING + trailing));
+        }
+        return;
+    }
+
+    match DECOMPOSITIONS.binary_search_by_key(&c, |&(c, _)| c) {
+        Ok(i) => DECOMPOSITIONS[i].1.chars().for_each(emit),
+        Err(_) => emit(c),
+    }
+}
+
+/// The primary composite of `first` followed by `second`, if there is one.
+fn compose(first: char, second: char) -> Option<char> {
+    let (first_code, second_code) = (first as u32, second as u32);
+    let leading = first_code.wrapping_sub(HANGUL_LEADING);
+    let vowel = second_code.wrapping_sub(HANGUL_VOWELS);
+    if leading < 19 && vowel < HANGUL_VOWEL_COUNT {
+        let syllable = (leading * HANGUL_VOWEL_COUNT + vowel) * HANGUL_TRAILING_COUNT;
+        return Some(hangul(HANGUL_SYLLABLES + syllable));
+    }
+    let syllable = first_code.wrapping_sub(HANGUL_SYLLABLES);
+    let trailing = second_code.wrapping_sub(HANGUL_TRAILING);
+    if syllable < HANGUL_SYLLABLE_COUNT && syllable % HANGUL_TRAILING_COUNT == 0
+            && (1..HANGUL_TRAILING_COUNT).contains(&trailing) {
+        return Some(hangul(first_code + trailing));
+    }
+
+    COMPOSITIONS.binary_search_by_key(&(first, second), |&(first, second, _)| (first, second))
+        .ok()
+        .map(|i| COMPOSITIONS[i].2)
+}
+
+fn combining_class(c: char) -> u8 {
+    let i = COMBINING_CLASSES.partition_point(|&(_, end, _)| end < c);
+    match COMBINING_CLASSES.get(i) {
+        Some(&(start, _, class)) if start <= c => class,
+        _ => 0,
+    }
+}
+
+fn hangul(code: u32) -> char {
+    char::from_u32(code).expect("Hangul jamo and syllables are valid characters")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn composes_letters_and_marks() {
+        assert_eq!(nfc("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(nfc("caf\u{e9}"), "caf\u{e9}");
+        // Vietnamese letters with two marks
+        assert_eq!(nfc("Vie\u{323}\u{302}t"), "Vi\u{1ec7}t");
+        assert_eq!(nfc("Vie\u{302}\u{323}t"), "Vi\u{1ec7}t");
+        // outside the Latin script
+        assert_eq!(nfc("\u{3b1}\u{301}"), "\u{3ac}");
+        assert_eq!(nfc("\u{43a}\u{301}"), "\u{45c}");
+    }
+
+    #[test]
+    fn orders_marks() {
+        // the dot below (class 220) goes before the dot above (class 230)
+        assert_eq!(nfc("s\u{307}\u{323}"), "\u{1e69}");
+        assert_eq!(nfc("q\u{307}\u{323}"), "q\u{323}\u{307}");
+        // marks of the same class keep their order
+        assert_eq!(nfc("a\u{301}\u{300}"), "\u{e1}\u{300}");
+        assert_eq!(nfc("a\u{300}\u{301}"), "\u{e0}\u{301}");
+    }
+
+    #[test]
+    fn replaces_singletons_and_keeps_exclusions() {
+        // Ångström sign and Kelvin sign
+        assert_eq!(nfc("\u{212b}"), "\u{c5}");
+        assert_eq!(nfc("\u{212a}"), "K");
+        // excluded from composition
+        assert_eq!(nfc("\u{915}\u{93c}"), "\u{915}\u{93c}");
+        assert_eq!(nfc("\u{958}"), "\u{915}\u{93c}");
+    }
+
+    #[test]
+    fn composes_hangul() {
+        assert_eq!(nfc("\u{1112}\u{1161}\u{11ab}"), "\u{d55c}");
+        assert_eq!(nfc("\u{d558}\u{11ab}"), "\u{d55c}");
+        assert_eq!(nfc("\u{d55c}"), "\u{d55c}");
+    }
+
+    #[test]
+    fn applies_rules() {
+        let all = Normalization::from_names("trailing-slash, case,nfc").expect("valid rules");
+        assert_eq!(all.apply("Cafe\u{301}/"), "caf\u{e9}");
+        let none = Normalization::from_names("none").expect("valid rules");
+        assert_eq!(none.apply("Cafe\u{301}/"), "Cafe\u{301}/");
+        assert!(Normalization::from_names("nfd").is_none());
+    }
+}
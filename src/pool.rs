@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct ThreadPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    sender: Option<mpsc::Sender<Job>>,
+    queued: Arc<AtomicUsize>,
+}
+
+
+impl ThreadPool {
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "Thread pool needs at least one worker");
+
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let workers = (0..size).map(|_| {
+            let receiver = Arc::clone(&receiver);
+            let queued = Arc::clone(&queued);
+            thread::spawn(move || loop {
+                // the lock is released again before the job runs
+                let job = match receiver.lock() {
+                    Ok(receiver) => receiver.recv(),
+                    Err(_) => break,
+                };
+
+                match job {
+                    Ok(job) => {
+                        queued.fetch_sub(1, Ordering::SeqCst);
+                        job()
+                    },
+                    Err(_) => break,
+                }
+            })
+        }).collect();
+
+        ThreadPool { workers, sender: Some(sender), queued }
+    }
+
+    pub fn execute<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        if let Some(sender) = &self.sender {
+            self.queued.fetch_add(1, Ordering::SeqCst);
+            if sender.send(Box::new(job)).is_err() {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Number of jobs waiting for a free worker, shared so running jobs can make room.
+    pub fn queued(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.queued)
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Stops accepting jobs and waits until all queued and in-flight jobs are done.
+    fn drop(&mut self) {
+        drop(self.sender.take());
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::prelude::*;
+
+
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    /// The decoded path of the target.
+    pub path: String,
+    /// The path of the target as sent by the client.
+    pub raw_path: String,
+    /// The query of the target as sent by the client.
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: HashMap<String, String>,
+    pub body: Option<Vec<u8>>,
+}
+
+pub enum ParseError {
+    Malformed,
+    HeadersTooLarge,
+    BodyTooLarge,
+    Io(io::Error),
+}
+
+
+const MAX_REQUEST_LINE: u64 = 8 * 1024;
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+
+
+impl Request {
+    /// Reads the next request from the connection.
+    ///
+    /// Returns `Ok(None)` if the client closed the connection before sending anything.
+    pub fn read(reader: &mut impl BufRead) -> Result<Option<Self>, ParseError> {
+        let request_line = match read_line(reader, MAX_REQUEST_LINE)? {
+            Line::Complete(line) => line,
+            Line::TooLong => return Err(ParseError::Malformed),
+            Line::Eof => return Ok(None),
+        };
+
+        let request_tokens: Vec<_> = request_line.split(' ').collect();
+        if request_tokens.len() != 3 {
+            return Err(ParseError::Malformed);
+        }
+        let (method, target, version) = (request_tokens[0], request_tokens[1], request_tokens[2]);
+
+        if method.is_empty() || !method.bytes().all(is_token_char)
+                || !matches!(version, "HTTP/1.0" | "HTTP/1.1")
+                || !(target.starts_with('/') || target == "*") {
+            return Err(ParseError::Malformed);
+        }
+
+        let (raw_path, query) = match target.split_once('?') {
+            Some((raw_path, query)) => (raw_path, Some(query)),
+            None => (target, None),
+        };
+        let path = percent_decode(raw_path, false).ok_or(ParseError::Malformed)?;
+
+        let headers = read_headers(reader)?;
+
+        if headers.contains_key("transfer-encoding") {
+            // only bodies with a known length are supported
+            return Err(ParseError::Malformed);
+        }
+        let body = match headers.get("content-length") {
+            Some(length) => {
+                let length: u64 = length.parse().map_err(|_| ParseError::Malformed)?;
+                if length > MAX_BODY_SIZE {
+                    return Err(ParseError::BodyTooLarge);
+                }
+                let mut body = Vec::new();
+                reader.take(length).read_to_end(&mut body)?;
+                if body.len() as u64 != length {
+                    return Err(ParseError::Malformed);
+                }
+                Some(body)
+            },
+            None => None,
+        };
+
+        let request = Request {
+            method: method.to_owned(),
+            target: target.to_owned(),
+            path,
+            raw_path: raw_path.to_owned(),
+            query: query.map(str::to_owned),
+            version: version.to_owned(),
+            headers,
+            body,
+        };
+        if request.query_params().is_none() {
+            return Err(ParseError::Malformed);
+        }
+        Ok(Some(request))
+    }
+
+    /// The parameters of the query, or `None` if it is not properly encoded.
+    ///
+    /// The query as sent is kept in `query`, as links forward it unchanged.
+    pub fn query_params(&self) -> Option<HashMap<String, String>> {
+        parse_query(self.query.as_deref().unwrap_or_default())
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(|x| x.as_str())
+    }
+
+    /// Whether the client wants the connection to stay open after this request.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("connection")
+            .map(|c| c.to_ascii_lowercase())
+            .unwrap_or_default();
+        let mut options = connection.split(',').map(str::trim);
+
+        if self.version == "HTTP/1.0" {
+            options.any(|o| o == "keep-alive")
+        } else {
+            !options.any(|o| o == "close")
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> Self {
+        ParseError::Io(e)
+    }
+}
+
+
+enum Line {
+    Complete(String),
+    TooLong,
+    Eof,
+}
+
+/// Reads a single CRLF or LF terminated line of at most `limit` bytes.
+fn read_line(reader: &mut impl BufRead, limit: u64) -> Result<Line, ParseError> {
+    let mut buf = Vec::new();
+    reader.take(limit).read_until(b'\n', &mut buf)?;
+
+    if buf.is_empty() {
+        return Ok(Line::Eof);
+    }
+    if buf.last() != Some(&b'\n') {
+        return Ok(if buf.len() as u64 >= limit { Line::TooLong } else { Line::Eof });
+    }
+    buf.pop();
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+
+    String::from_utf8(buf)
+        .map(Line::Complete)
+        .map_err(|_| ParseError::Malformed)
+}
+
+fn read_headers(reader: &mut impl BufRead) -> Result<HashMap<String, String>, ParseError> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut remaining = MAX_HEADER_SIZE;
+
+    for _ in 0..=MAX_HEADERS {
+        let line = match read_line(reader, remaining as u64 + 2)? {
+            Line::Complete(line) => line,
+            Line::TooLong => return Err(ParseError::HeadersTooLarge),
+            Line::Eof => return Err(ParseError::Malformed),
+        };
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        remaining = remaining.checked_sub(line.len()).ok_or(ParseError::HeadersTooLarge)?;
+
+        // obsolete line folding starts with whitespace and is rejected as well
+        let (name, value) = line.split_once(':').ok_or(ParseError::Malformed)?;
+        if name.is_empty() || !name.bytes().all(is_token_char) {
+            return Err(ParseError::Malformed);
+        }
+
+        let name = name.to_ascii_lowercase();
+        let value = value.trim();
+        if name == "content-length" && headers.get(&name).is_some_and(|v| v != value) {
+            return Err(ParseError::Malformed);
+        }
+        headers.entry(name)
+            .and_modify(|v| if v != value { v.push_str(", "); v.push_str(value) })
+            .or_insert_with(|| value.to_owned());
+    }
+
+    Err(ParseError::HeadersTooLarge)
+}
+
+fn is_token_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+/// Parses an `application/x-www-form-urlencoded` string, as used for query strings.
+pub fn parse_query(query: &str) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        params.insert(percent_decode(key, true)?, percent_decode(value, true)?);
+    }
+
+    Some(params)
+}
+
+/// Decodes `%XX` escapes, returning `None` for invalid escapes or non-UTF-8 results.
+pub fn percent_decode(s: &str, plus_as_space: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hi = (iter.next()? as char).to_digit(16)?;
+                let lo = (iter.next()? as char).to_digit(16)?;
+                bytes.push((hi * 16 + lo) as u8);
+            },
+            b'+' if plus_as_space => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Encodes every byte as `%XX` except for ASCII letters, digits, `-._~` and those in `keep`.
+pub fn percent_encode(s: &str, keep: &[u8]) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || keep.contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &[u8]) -> Result<Option<Request>, ParseError> {
+        Request::read(&mut io::BufReader::new(input))
+    }
+
+    #[test]
+    fn reads_request() {
+        let request = read(b"GET /caf%C3%A9/x?q=a%20b HTTP/1.1\r\nHost: example.com\r\nX-A: 1\r\nx-a: 2\r\n\r\n")
+            .ok().flatten().expect("valid request");
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/café/x");
+        assert_eq!(request.raw_path, "/caf%C3%A9/x");
+        assert_eq!(request.query.as_deref(), Some("q=a%20b"));
+        assert_eq!(request.query_params().and_then(|p| p.get("q").cloned()).as_deref(), Some("a b"));
+        assert_eq!(request.header("HOST"), Some("example.com"));
+        assert_eq!(request.header("x-a"), Some("1, 2"));
+        assert!(request.body.is_none());
+        assert!(request.keep_alive());
+    }
+
+    #[test]
+    fn reads_body() {
+        let request = read(b"POST /_api/links HTTP/1.1\r\nContent-Length: 5\r\n\r\ntoken")
+            .ok().flatten().expect("valid request");
+        assert_eq!(request.body.as_deref(), Some(&b"token"[..]));
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let requests: &[&[u8]] = &[
+            b"GET /\r\n\r\n",
+            b"GET / HTTP/2.0\r\n\r\n",
+            b"GET example.com HTTP/1.1\r\n\r\n",
+            b"G(T / HTTP/1.1\r\n\r\n",
+            b"GET /%zz HTTP/1.1\r\n\r\n",
+            b"GET /?q=%zz HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nNo colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\n Folded: value\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort",
+        ];
+        for request in requests {
+            assert!(matches!(read(request), Err(ParseError::Malformed)), "{}", String::from_utf8_lossy(request));
+        }
+
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_REQUEST_LINE as usize));
+        assert!(matches!(read(long_line.as_bytes()), Err(ParseError::Malformed)));
+    }
+
+    #[test]
+    fn rejects_large_headers() {
+        let large = format!("GET / HTTP/1.1\r\nX-Large: {}\r\n\r\n", "a".repeat(MAX_HEADER_SIZE));
+        assert!(matches!(read(large.as_bytes()), Err(ParseError::HeadersTooLarge)));
+
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(MAX_HEADERS + 1));
+        assert!(matches!(read(many.as_bytes()), Err(ParseError::HeadersTooLarge)));
+    }
+
+    #[test]
+    fn rejects_large_body() {
+        let request = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_SIZE + 1);
+        assert!(matches!(read(request.as_bytes()), Err(ParseError::BodyTooLarge)));
+    }
+
+    #[test]
+    fn reads_pipelined_requests() {
+        let mut reader = io::BufReader::new(&b"GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 2\r\n\r\nxyGET /c HTTP/1.0\r\n\r\n"[..]);
+        let mut next = || Request::read(&mut reader).ok().flatten().map(|r| (r.path, r.body));
+        assert_eq!(next(), Some(("/a".to_owned(), None)));
+        assert_eq!(next(), Some(("/b".to_owned(), Some(b"xy".to_vec()))));
+        assert_eq!(next(), Some(("/c".to_owned(), None)));
+        assert!(matches!(Request::read(&mut reader), Ok(None)));
+    }
+
+    #[test]
+    fn keep_alive_depends_on_version() {
+        let keep_alive = |input: &[u8]| read(input).ok().flatten().expect("valid request").keep_alive();
+        assert!(keep_alive(b"GET / HTTP/1.1\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        assert!(!keep_alive(b"GET / HTTP/1.0\r\n\r\n"));
+        assert!(keep_alive(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n"));
+    }
+}
@@ -1,138 +1,822 @@
-use std::collections::HashMap;
+use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::net::{TcpListener, TcpStream};
-use std::time::Duration;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::store::Store;
+use crate::api;
+use crate::log::{AccessLog, Entry};
+use crate::pool::ThreadPool;
+use crate::request::{percent_decode, percent_encode, ParseError, Request};
+use crate::signal;
+use crate::stats::{html_escape, Stats};
+use crate::store::{Link, Redirect, Store, Version};
+use crate::token::Generator;
+use crate::watch;
+#[cfg(feature = "tls")]
+use crate::tls;
 
 
 pub struct Server {
     listener: TcpListener,
-    store: Store
+    state: Arc<State>,
+    pool: ThreadPool,
+    #[cfg(feature = "tls")]
+    tls: Option<tls::Acceptor>,
 }
 
-enum ResponseType {
+pub struct Options {
+    pub workers: usize,
+    pub redirect: Redirect,
+    pub cache_control: Option<String>,
+    pub pages: Pages,
+    pub max_shrink: usize,
+    pub auto_redirect: AutoRedirect,
+    pub api_secret: Option<String>,
+    pub generator: Generator,
+    pub access_log: Option<AccessLog>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<tls::Acceptor>,
+}
+
+/// Everything the workers share while handling requests.
+pub struct State {
+    pub store: RwLock<Store>,
+    pub stats: Mutex<Stats>,
+    pub stats_path: String,
+    pub redirect: Redirect,
+    pub cache_control: Option<String>,
+    pub pages: Pages,
+    pub max_shrink: usize,
+    pub auto_redirect: AutoRedirect,
+    /// Locked before `store` where both are needed.
+    pub reloads: Mutex<ReloadStatus>,
+    pub api_secret: Option<String>,
+    pub generator: Generator,
+    pub access_log: Option<AccessLog>,
+    pub started: Instant,
+    pub requests: AtomicUsize,
+}
+
+/// Outcome of the latest reloads of the mapping file.
+pub struct ReloadStatus {
+    /// Time and number of links of the last reload that was put to use.
+    pub last_success: (SystemTime, usize),
+    /// Time and reason of the last reload that was rejected.
+    pub last_failure: Option<(SystemTime, String)>,
+    /// Version of the rejected files, which is not tried again.
+    rejected: Option<Version>,
+}
+
+/// Which unknown tokens are redirected to a token they match.
+#[derive(Clone, Copy, Default)]
+pub struct AutoRedirect {
+    /// The only token that is the same except for case.
+    pub case: bool,
+    /// The only token starting with the requested one.
+    pub prefix: bool,
+}
+
+/// Pages served by lisho itself, either built in or from a template directory.
+pub struct Pages {
+    index: String,
+    not_found: String,
+    redirect: String,
+    style: String,
+}
+
+pub enum ResponseType {
     Ok,
+    Created,
+    MovedPermanently,
+    Found,
+    SeeOther,
     TemporaryRedirect,
     PermanentRedirect,
     BadRequest,
+    Unauthorized,
     NotFound,
+    MethodNotAllowed,
+    Conflict,
+    Gone,
+    PayloadTooLarge,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+}
+
+pub struct Response {
+    response_type: ResponseType,
+    headers: Vec<(&'static str, String)>,
+    content: Option<String>,
 }
 
 
 const HTTP_VERSION: &str = "HTTP/1.1";
-const LET_CLIENTS_CACHE: bool = true;
+/// How long a client may take to send a request once it started.
+const REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often idle connections check whether another connection needs their worker.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_REQUESTS_PER_CONNECTION: usize = 100;
 const NOT_FOUND_PAGE: &str = include_str!("404.html");
 const REDIRECTION_PAGE: &str = include_str!("redirect.html");
 const INDEX_PAGE: &str = include_str!("index.html");
 const STYLE_SHEET: &str = include_str!("style.css");
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+const BUILTIN_PATHS: &[&str] = &["", "index.html", "style.css"];
+/// Characters that may stay unencoded when forwarding the path and query of a request.
+const FORWARD_SAFE: &[u8] = b"/?:@!$&'()*+,;=%";
+const MAX_SUGGESTIONS: usize = 3;
+const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+const SIGNAL_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
 
 impl Server {
-    pub fn init(addr: &str, store: Store) -> io::Result<Self> {
+    pub fn init(addr: &str, store: Store, options: Options) -> io::Result<Self> {
+        let stats_path = format!("{}.stats", store.file_path().trim_end_matches('/'));
+        let reloads = ReloadStatus {
+            last_success: (SystemTime::now(), store.len()),
+            last_failure: None,
+            rejected: None,
+        };
+        let state = Arc::new(State {
+            stats: Mutex::new(Stats::load(&stats_path)?),
+            stats_path,
+            store: RwLock::new(store),
+            redirect: options.redirect,
+            cache_control: options.cache_control,
+            pages: options.pages,
+            max_shrink: options.max_shrink,
+            auto_redirect: options.auto_redirect,
+            reloads: Mutex::new(reloads),
+            api_secret: options.api_secret,
+            generator: options.generator,
+            access_log: options.access_log,
+            started: Instant::now(),
+            requests: AtomicUsize::new(0),
+        });
+
+        let listener = TcpListener::bind(addr)?;
+
+        let (paths_state, reload_state) = (Arc::clone(&state), Arc::clone(&state));
+        watch::spawn(
+            move || paths_state.store.read().map(|s| s.watched_paths()).unwrap_or_default(),
+            move || reload_state.reload_store(false),
+        );
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &options.tls {
+            let (paths_tls, reload_tls) = (tls.clone(), tls.clone());
+            watch::spawn(move || paths_tls.watched_paths(), move || reload_tls.reload_if_changed());
+        }
+
+        let signal_state = Arc::clone(&state);
+        let wake_addr = wake_addr(listener.local_addr()?);
+        thread::spawn(move || {
+            let mut hangups = signal::hangups();
+            loop {
+                thread::sleep(SIGNAL_CHECK_INTERVAL);
+                if signal::shutdown_requested() {
+                    // the accept loop only notices once it gets a connection
+                    let _ = TcpStream::connect(wake_addr);
+                    return;
+                }
+                if signal::hangups() != hangups {
+                    hangups = signal::hangups();
+                    println!("Received SIGHUP, reloading store");
+                    signal_state.reload_store(true);
+                }
+            }
+        });
+
+        let stats_state = Arc::clone(&state);
+        thread::spawn(move || loop {
+            thread::sleep(STATS_SAVE_INTERVAL);
+            stats_state.save_stats();
+        });
+
         Ok(Server {
-            listener: TcpListener::bind(addr)?,
-            store,
+            listener,
+            state,
+            pool: ThreadPool::new(options.workers),
+            #[cfg(feature = "tls")]
+            tls: options.tls,
         })
     }
 
-    pub fn run(&mut self) {
+    pub fn workers(&self) -> usize {
+        self.pool.size()
+    }
+
+    /// Serves connections until SIGTERM or SIGINT is received.
+    ///
+    /// Connections that have already been accepted are still answered before returning.
+    pub fn run(self) {
         for stream in self.listener.incoming() {
-            if let Ok(res) = self.store.has_changed() {
-                if res {
-                    let status = self.store.refresh();
-                    if status.is_ok() {
-                        let nlinks = self.store.len();
-                        println!("Reloading store ({nlinks} links)");
-                    }
+            if signal::shutdown_requested() {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            stream.set_read_timeout(Some(REQUEST_TIMEOUT))
+                .expect("Read timeout may not be zero");
+            let state = Arc::clone(&self.state);
+            let queued = self.pool.queued();
+
+            #[cfg(feature = "tls")]
+            if let Some(tls) = &self.tls {
+                if let Ok(stream) = tls.accept(stream) {
+                    self.pool.execute(move || {
+                        let _ = Self::handle_connection(&state, stream, &queued);
+                    });
                 }
+                continue;
             }
 
-            if let Ok(stream) = stream {
-                stream.set_read_timeout(Some(Duration::from_millis(500)))
-                    .expect("Read timeout may not be zero");
-                let _ = self.handle_connection(stream);
+            self.pool.execute(move || {
+                let _ = Self::handle_connection(&state, stream, &queued);
+            });
+        }
+
+        println!("Shutting down, waiting for open connections");
+        drop(self.listener);
+        drop(self.pool);
+        self.state.save_stats();
+
+        let requests = self.state.requests.load(Ordering::SeqCst);
+        let uptime = self.state.started.elapsed().as_secs();
+        println!("Stopped after {uptime} s and {requests} requests");
+    }
+
+    /// Answers the requests on a connection.
+    ///
+    /// Connections are only kept alive while no other connection is waiting for a worker.
+    fn handle_connection(state: &State, connection: impl Connection, queued: &AtomicUsize) -> io::Result<()> {
+        let client = connection.socket().peer_addr().ok().map(|a| a.ip());
+        let mut reader = BufReader::new(connection);
+
+        for nrequest in 1..=MAX_REQUESTS_PER_CONNECTION {
+            let request = match Request::read(&mut reader) {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(ParseError::Io(e)) => return Err(e),
+                Err(e) => {
+                    let response_type = match e {
+                        ParseError::HeadersTooLarge => ResponseType::RequestHeaderFieldsTooLarge,
+                        ParseError::BodyTooLarge => ResponseType::PayloadTooLarge,
+                        _ => ResponseType::BadRequest,
+                    };
+                    // the rest of the stream can not be trusted anymore
+                    let response = Response::new(response_type);
+                    let size = Self::send_response(reader.get_mut(), &response, false, true)?;
+                    state.log(client, None, &response, size);
+                    return Ok(());
+                },
+            };
+
+            state.requests.fetch_add(1, Ordering::Relaxed);
+            let keep_alive = nrequest < MAX_REQUESTS_PER_CONNECTION && request.keep_alive()
+                && !signal::shutdown_requested() && queued.load(Ordering::SeqCst) == 0;
+            let response = Self::handle_request(state, &request);
+            let include_content = request.method != "HEAD";
+            let size = Self::send_response(reader.get_mut(), &response, keep_alive, include_content)?;
+            state.log(client, Some(&request), &response, size);
+            if !keep_alive {
+                return Ok(());
+            }
+
+            if !Self::wait_for_request(&mut reader, queued)? {
+                return Ok(());
             }
         }
+
+        Ok(())
     }
 
-    fn handle_connection(&self, stream: TcpStream) -> io::Result<()> {
-        let reader = BufReader::new(&stream);
-        let request_line = match reader.lines().next() {
-            Some(line) => line?,
-            None => return Ok(()),
+    /// Waits for the next request on a kept-alive connection.
+    ///
+    /// Returns `false` if the client closes the connection, stays idle for `IDLE_TIMEOUT` or
+    /// another connection is waiting for the worker in the meantime.
+    fn wait_for_request(reader: &mut BufReader<impl Connection>, queued: &AtomicUsize) -> io::Result<bool> {
+        if !reader.buffer().is_empty() {
+            return Ok(true);
+        }
+
+        reader.get_ref().socket().set_read_timeout(Some(IDLE_CHECK_INTERVAL))?;
+        let idle_since = Instant::now();
+        let result = loop {
+            match reader.fill_buf() {
+                Ok(buffer) => break Ok(!buffer.is_empty()),
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if queued.load(Ordering::SeqCst) > 0 || idle_since.elapsed() >= IDLE_TIMEOUT
+                            || signal::shutdown_requested() {
+                        break Ok(false);
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => break Err(e),
+            }
         };
+        reader.get_ref().socket().set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        result
+    }
 
-        let request_tokens: Vec<_> = request_line.split(' ').collect();
+    fn handle_request(state: &State, request: &Request) -> Response {
+        if api::handles(&request.path) {
+            return api::handle(state, request);
+        }
 
+        match request.method.as_str() {
+            "GET" | "HEAD" => (),
+            "OPTIONS" => return Response::new(ResponseType::Ok)
+                .with_header("Allow", ALLOWED_METHODS)
+                .with_content(""),
+            _ => return Response::new(ResponseType::MethodNotAllowed)
+                .with_header("Allow", ALLOWED_METHODS),
+        }
 
-        if request_tokens.len() != 3 {
-            Self::send_response(stream, ResponseType::BadRequest, HashMap::new(), None)
-        } else if request_tokens[0] != "GET" {
-            Self::send_response(stream, ResponseType::NotFound, HashMap::new(), None)
+        let path = request.path.as_str();
+        let token = match path.strip_prefix('/') {
+            Some(token) => token,
+            None => return Response::new(ResponseType::BadRequest),
+        };
+
+        let store = match state.store.read() {
+            Ok(store) => store,
+            Err(_) => return Response::new(ResponseType::InternalServerError),
+        };
+        let now = SystemTime::now();
+        let (token, link, rest) = match find_link(&store, token, &request.raw_path) {
+            Some((_, link, _)) if link.is_pending(now) => return Self::not_found(state, &store, token),
+            Some(found) => found,
+            None => return match path {
+                "/" | "/index.html" => Response::new(ResponseType::Ok).with_content(state.pages.index.as_str()),
+                "/style.css" => Response::new(ResponseType::Ok).with_content(state.pages.style.as_str()),
+                _ => Self::not_found(state, &store, token),
+            },
+        };
+        drop(store);
+
+        if link.is_expired(now) {
+            return Self::gone(state, &token);
+        }
+        state.print_request(&format!("Token requested: {token}"));
+        if let Ok(mut stats) = state.stats.lock() {
+            stats.hit(&token);
+        }
+        let url = if link.is_template() {
+            link.fill(&arguments(rest, request.query.as_deref()))
+        } else if link.forward {
+            forward_url(&link.url, rest, request.query.as_deref())
         } else {
-            let path = request_tokens[1];
-            let token = &path[1..];
+            link.url.clone()
+        };
+        let content = str::replace(&state.pages.redirect, "REDIRECTION_TOKEN", &html_escape(&token));
+        let content = str::replace(&content, "REDIRECTION_LINK", &html_escape(&url));
 
-            if let Some(link) = self.store.get(token) {
-                println!("Token requested: {token}");
-                let content = str::replace(REDIRECTION_PAGE, "REDIRECTION_TOKEN", token);
-                let content = str::replace(&content, "REDIRECTION_LINK", link);
+        // browsers remember permanent redirects, which would outlive the link
+        let default_redirect = match state.redirect {
+            redirect if link.is_scheduled() && redirect.is_permanent() => Redirect::Temporary,
+            redirect => redirect,
+        };
+        let response_type = match link.redirect.unwrap_or(default_redirect) {
+            Redirect::MovedPermanently => ResponseType::MovedPermanently,
+            Redirect::Found => ResponseType::Found,
+            Redirect::SeeOther => ResponseType::SeeOther,
+            Redirect::Temporary => ResponseType::TemporaryRedirect,
+            Redirect::Permanent => ResponseType::PermanentRedirect,
+        };
+        let mut response = Response::new(response_type)
+            .with_header("Location", url)
+            .with_content(content);
+        if let Some(cache_control) = &state.cache_control {
+            response = response.with_header("Cache-Control", cache_control.as_str());
+        }
+        response
+    }
 
-                let response_type = if LET_CLIENTS_CACHE {
-                    ResponseType::PermanentRedirect
-                } else {
-                    ResponseType::TemporaryRedirect
-                };
-                let headers = HashMap::from([("Location", link)]);
-                Self::send_response(stream, response_type, headers, Some(&content))
-            } else {
-                match path {
-                    "/" | "/index.html" =>
-                        Self::send_response(stream, ResponseType::Ok, HashMap::new(), Some(INDEX_PAGE)),
-                    "/style.css" =>
-                        Self::send_response(stream, ResponseType::Ok, HashMap::new(), Some(STYLE_SHEET)),
-                    _ => {
-                        let content = str::replace(NOT_FOUND_PAGE, "REDIRECTION_TOKEN", token);
-                        Self::send_response(stream, ResponseType::NotFound, HashMap::new(), Some(&content))
-                    },
-                }
-            }
+    /// Answers a request for a token without a link, suggesting similar tokens.
+    ///
+    /// If auto-redirects are enabled and only one token matches, the client is sent there instead.
+    fn not_found(state: &State, store: &Store, token: &str) -> Response {
+        if let Ok(mut stats) = state.stats.lock() {
+            stats.miss(&store.normalize(token));
+        }
+
+        let auto_redirect = &state.auto_redirect;
+        let guess = match (store.case_insensitive_matches(token), store.prefix_matches(token)) {
+            (matches, _) if auto_redirect.case && matches.len() == 1 => Some(matches[0]),
+            (_, matches) if auto_redirect.prefix && matches.len() == 1 && !token.is_empty() => Some(matches[0]),
+            _ => None,
+        };
+        if let Some(guess) = guess {
+            state.print_request(&format!("Token requested: {token}, redirecting to {guess}"));
+            // temporary, as the guess changes with the links
+            return Response::new(ResponseType::TemporaryRedirect)
+                .with_header("Location", format!("/{}", percent_encode(guess, b"/")));
+        }
+
+        let namespace = store.namespace(token);
+        let message = match namespace {
+            Some(namespace) => format!("The namespace \"{namespace}\" has no link for \"{token}\"."),
+            None => format!("There does not seem to be a page or redirection for the token \"{token}\"."),
+        };
+        let suggestions: Vec<_> = store.similar(token, MAX_SUGGESTIONS).iter()
+            .map(|t| format!("<a href=\"/{}\">/{}</a>", percent_encode(t, b"/"), html_escape(t)))
+            .collect();
+        let suggestions = match suggestions.split_last() {
+            Some((last, [])) => format!("Did you mean {last}?"),
+            Some((last, others)) => format!("Did you mean {} or {last}?", others.join(", ")),
+            None => String::new(),
+        };
+
+        let content = state.pages.not_found(token, namespace, &message, &suggestions);
+        Response::new(ResponseType::NotFound).with_content(content)
+    }
+
+    /// Answers a request for a link that has expired.
+    fn gone(state: &State, token: &str) -> Response {
+        if let Ok(mut stats) = state.stats.lock() {
+            stats.miss(token);
+        }
+        state.print_request(&format!("Token requested: {token}, which has expired"));
+
+        let message = format!("The link for the token \"{token}\" has expired.");
+        let content = state.pages.not_found(token, None, &message, "");
+        Response::new(ResponseType::Gone).with_content(content)
+    }
+
+    /// Writes the response to the stream, leaving out the content for HEAD requests.
+    ///
+    /// `Content-Length` always describes the full content.
+    /// Returns the number of content bytes sent.
+    fn send_response(stream: &mut impl Write, response: &Response, keep_alive: bool,
+                        include_content: bool) -> io::Result<usize> {
+        let code_and_reason = response.response_type.code_and_reason();
+
+        let content = match &response.content {
+            Some(content) => content,
+            None => code_and_reason,
+        };
+        let length = content.len();
+
+        // Status line
+        let mut message = format!("{HTTP_VERSION} {code_and_reason}\r\n");
+
+        // Headers
+        for (key, value) in &response.headers {
+            message.push_str(&format!("{key}: {value}\r\n"));
+        }
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        message.push_str(&format!("Connection: {connection}\r\n"));
+        message.push_str(&format!("Content-Length: {length}\r\n\r\n"));
+
+        // Content
+        if include_content {
+            message.push_str(content);
+        }
+
+        // a single write keeps TLS from sending a record per line
+        stream.write_all(message.as_bytes())?;
+        stream.flush()?;
+        Ok(if include_content { length } else { 0 })
+    }
+}
+
+/// Looks up the link for `token`, or else for the longest prefix of it ending before a `/`
+/// whose link forwards the rest of the path or is a template.
+///
+/// Prefixes are taken from `raw_path` as sent by the client, so an encoded `%2F` does not end one.
+/// Returns the token that matched, its link and the still encoded rest of the path.
+fn find_link<'a>(store: &Store, token: &str, raw_path: &'a str) -> Option<(String, Link, &'a str)> {
+    if let Some(link) = store.get(token) {
+        return Some((store.normalize(token), link.clone(), ""));
+    }
+
+    let raw_token = raw_path.strip_prefix('/')?;
+    for (end, _) in raw_token.rmatch_indices('/') {
+        let prefix = match percent_decode(&raw_token[..end], false) {
+            Some(prefix) => prefix,
+            None => continue,
+        };
+        if let Some(link) = store.get(&prefix).filter(|l| l.forward || l.is_template()) {
+            return Some((store.normalize(&prefix), link.clone(), &raw_token[end..]));
         }
     }
+    None
+}
+
+/// The arguments for a template link: the segments in the rest of the path, or else the query.
+fn arguments(rest: &str, query: Option<&str>) -> Vec<String> {
+    let arguments: Vec<_> = rest.split('/')
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| percent_decode(s, false))
+        .collect();
+    match query.filter(|q| arguments.is_empty() && !q.is_empty()) {
+        Some(query) => percent_decode(query, true).into_iter().collect(),
+        None => arguments,
+    }
+}
+
+/// Appends the rest of a request path and its query to `url`, keeping its fragment at the end.
+fn forward_url(url: &str, rest: &str, query: Option<&str>) -> String {
+    let (url, fragment) = match url.split_once('#') {
+        Some((url, fragment)) => (url, Some(fragment)),
+        None => (url, None),
+    };
+    let (path, url_query) = match url.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (url, None),
+    };
+
+    let mut forwarded = path.to_owned();
+    if forwarded.ends_with('/') && rest.starts_with('/') {
+        forwarded.pop();
+    }
+    forwarded.push_str(&percent_encode(rest, FORWARD_SAFE));
+
+    let query = query.map(|q| percent_encode(q, FORWARD_SAFE));
+    let queries: Vec<_> = [url_query, query.as_deref()].into_iter().flatten().filter(|q| !q.is_empty()).collect();
+    if !queries.is_empty() {
+        forwarded.push('?');
+        forwarded.push_str(&queries.join("&"));
+    }
+    if let Some(fragment) = fragment {
+        forwarded.push('#');
+        forwarded.push_str(fragment);
+    }
+    forwarded
+}
+
+/// Where to connect to reach a listener bound to `addr`, which may be a wildcard address.
+fn wake_addr(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port()),
+        _ => addr,
+    }
+}
+
+/// Whether a token would collide with pages served by the server itself.
+pub fn is_reserved(token: &str) -> bool {
+    is_builtin(token) || api::handles(&format!("/{token}"))
+}
+
+/// Whether `token` names one of the built-in pages, which links may replace.
+pub fn is_builtin(token: &str) -> bool {
+    BUILTIN_PATHS.contains(&token)
+}
+
+/// A client connection, either plain or encrypted.
+trait Connection: Read + Write {
+    fn socket(&self) -> &TcpStream;
+}
+
+impl Connection for TcpStream {
+    fn socket(&self) -> &TcpStream {
+        self
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Connection for tls::Stream {
+    fn socket(&self) -> &TcpStream {
+        &self.sock
+    }
+}
 
-    fn send_response(mut stream: TcpStream, response_type: ResponseType,
-                        headers: HashMap<&str, &str>, content: Option<&str>) -> io::Result<()> {
+impl ResponseType {
+    fn code_and_reason(&self) -> &'static str {
         use ResponseType::*;
 
-        let code_and_reason = match response_type {
+        match self {
             Ok => "200 OK",
+            Created => "201 CREATED",
+            MovedPermanently => "301 MOVED PERMANENTLY",
+            Found => "302 FOUND",
+            SeeOther => "303 SEE OTHER",
             TemporaryRedirect => "307 TEMPORARY REDIRECT",
             PermanentRedirect => "308 PERMANENT REDIRECT",
             BadRequest => "400 BAD REQUEST",
+            Unauthorized => "401 UNAUTHORIZED",
             NotFound => "404 NOT FOUND",
+            MethodNotAllowed => "405 METHOD NOT ALLOWED",
+            Conflict => "409 CONFLICT",
+            Gone => "410 GONE",
+            PayloadTooLarge => "413 PAYLOAD TOO LARGE",
+            RequestHeaderFieldsTooLarge => "431 REQUEST HEADER FIELDS TOO LARGE",
+            InternalServerError => "500 INTERNAL SERVER ERROR",
+        }
+    }
+
+    fn code(&self) -> u16 {
+        self.code_and_reason()[..3].parse().unwrap_or(0)
+    }
+}
+
+impl State {
+    /// Prints a short note about a request, unless the access log records them already.
+    fn print_request(&self, message: &str) {
+        if self.access_log.is_none() {
+            println!("{message}");
+        }
+    }
+
+    fn log(&self, client: Option<IpAddr>, request: Option<&Request>, response: &Response, size: usize) {
+        let access_log = match &self.access_log {
+            Some(access_log) => access_log,
+            None => return,
         };
 
-        let content = match content {
-            Some(content) => content,
-            None => code_and_reason,
+        access_log.write(&Entry {
+            time: SystemTime::now(),
+            client,
+            method: request.map(|r| r.method.as_str()),
+            target: request.map(|r| r.target.as_str()),
+            version: request.map(|r| r.version.as_str()),
+            status: response.response_type.code(),
+            size,
+            referer: request.and_then(|r| r.header("referer")),
+            user_agent: request.and_then(|r| r.header("user-agent")),
+        });
+    }
+
+    /// Replaces the store if the mapping file has changed and the new version looks sane.
+    ///
+    /// Files that fail strict parsing or lose more than `max_shrink` percent of their links
+    /// are rejected, keeping the previous links in use until the file changes again.
+    /// With `force` the files are read even if they seem unchanged, as modification times
+    /// are not precise enough to notice every edit.
+    /// The new mappings are read while the old ones are still being served,
+    /// so the write lock is only held for the final swap.
+    pub fn reload_store(&self, force: bool) {
+        // held throughout, so API edits can not change the store in the meantime
+        let mut reloads = match self.reloads.lock() {
+            Ok(reloads) => reloads,
+            Err(_) => return,
         };
-        let length = content.len();
 
-        // Status line
-        write!(stream, "{HTTP_VERSION} {code_and_reason}\r\n")?;
+        let (fresh, version) = {
+            let store = match self.store.read() {
+                Ok(store) => store,
+                Err(_) => return,
+            };
+            let version = store.disk_version();
+            let rejected = reloads.rejected.as_ref() == Some(&version);
+            if !force && (rejected || version == *store.version()) {
+                return;
+            }
 
-        // Headers
-        for (key, value) in &headers {
-            write!(stream, "{key}: {value}\r\n")?;
+            let fresh = store.reload_strict().and_then(|fresh| {
+                let (old, new) = (store.len(), fresh.len());
+                if new * 100 < old * (100 - self.max_shrink) {
+                    Err(format!("The changes would drop {} of {old} links", old - new))
+                } else {
+                    Ok(fresh)
+                }
+            });
+            (fresh, version)
+        };
+
+        let fresh = match fresh {
+            Ok(fresh) => fresh,
+            Err(reason) => {
+                eprintln!("Keeping previous links, not reloading store: {reason}");
+                reloads.last_failure = Some((SystemTime::now(), reason));
+                reloads.rejected = Some(version);
+                return;
+            },
+        };
+
+        if let Ok(mut store) = self.store.write() {
+            *store = fresh;
+            let nlinks = store.len();
+            reloads.succeeded(nlinks);
+            println!("Reloading store ({nlinks} links)");
         }
-        write!(stream, "Content-Length: {length}\r\n\r\n")?;
+    }
 
-        // Content
-        write!(stream, "{content}")?;
+    pub fn save_stats(&self) {
+        let result = match self.stats.lock() {
+            Ok(mut stats) => stats.save(&self.stats_path),
+            Err(_) => return,
+        };
+        if let Err(e) = result {
+            eprintln!("Unable to save statistics: {e}");
+        }
+    }
+}
+
+impl ReloadStatus {
+    /// Records that the store now holds `nlinks` links read from the files.
+    pub fn succeeded(&mut self, nlinks: usize) {
+        self.last_success = (SystemTime::now(), nlinks);
+        self.rejected = None;
+    }
+}
+
+impl AutoRedirect {
+    /// Parses a comma separated list of `case` and `prefix`, or `none`.
+    pub fn from_names(names: &str) -> Option<Self> {
+        let mut auto_redirect = AutoRedirect::default();
+        for name in names.split(',').map(str::trim) {
+            match name {
+                "case" => auto_redirect.case = true,
+                "prefix" => auto_redirect.prefix = true,
+                "none" => (),
+                _ => return None,
+            }
+        }
+        Some(auto_redirect)
+    }
+}
+
+impl Pages {
+    /// Loads the pages found in `dir`, falling back to the built-in ones for the others.
+    pub fn load(dir: Option<&str>) -> io::Result<Self> {
+        if let Some(dir) = dir {
+            fs::read_dir(dir)?;
+        }
+
+        let load = |name: &str, default: &str| match dir {
+            Some(dir) => match fs::read_to_string(Path::new(dir).join(name)) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(default.to_owned()),
+                result => result,
+            },
+            None => Ok(default.to_owned()),
+        };
+
+        Ok(Pages {
+            index: load("index.html", INDEX_PAGE)?,
+            not_found: load("404.html", NOT_FOUND_PAGE)?,
+            redirect: load("redirect.html", REDIRECTION_PAGE)?,
+            style: load("style.css", STYLE_SHEET)?,
+        })
+    }
+
+    /// The 404 page, with `suggestions` as HTML and everything else still to be escaped.
+    fn not_found(&self, token: &str, namespace: Option<&str>, message: &str, suggestions: &str) -> String {
+        let content = str::replace(&self.not_found, "NOT_FOUND_MESSAGE", &html_escape(message));
+        let content = str::replace(&content, "NOT_FOUND_SUGGESTIONS", suggestions);
+        let content = str::replace(&content, "REDIRECTION_NAMESPACE", &html_escape(namespace.unwrap_or("")));
+        str::replace(&content, "REDIRECTION_TOKEN", &html_escape(token))
+    }
+}
+
+impl Response {
+    pub fn new(response_type: ResponseType) -> Self {
+        Response { response_type, headers: Vec::new(), content: None }
+    }
+
+    pub fn with_header(mut self, key: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((key, value.into()));
+        self
+    }
+
+    pub fn with_content(mut self, content: impl Into<String>) -> Self {
+        self.content = Some(content.into());
+        self
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forwards_rest_of_path() {
+        assert_eq!(forward_url("https://github.com", "/jzbor/lisho", None), "https://github.com/jzbor/lisho");
+        assert_eq!(forward_url("https://github.com", "", None), "https://github.com");
+    }
+
+    #[test]
+    fn forwards_to_target_with_trailing_slash() {
+        assert_eq!(forward_url("https://docs.example.com/v2/", "/install", None), "https://docs.example.com/v2/install");
+        assert_eq!(forward_url("https://docs.example.com/v2/", "/", None), "https://docs.example.com/v2/");
+        assert_eq!(forward_url("https://docs.example.com/v2/", "", None), "https://docs.example.com/v2/");
+    }
+
+    #[test]
+    fn keeps_encoding_of_rest() {
+        assert_eq!(forward_url("https://github.com", "/a%2Fb", None), "https://github.com/a%2Fb");
+        assert_eq!(forward_url("https://github.com", "/a b\"<>", None), "https://github.com/a%20b%22%3C%3E");
+        assert_eq!(forward_url("https://github.com", "/caf\u{e9}", None), "https://github.com/caf%C3%A9");
+    }
 
-        stream.flush()
+    #[test]
+    fn forwards_query_and_keeps_fragment() {
+        assert_eq!(forward_url("https://github.com", "", Some("tab=stars")), "https://github.com?tab=stars");
+        assert_eq!(forward_url("https://github.com", "", Some("")), "https://github.com");
+        assert_eq!(forward_url("https://e.example/p?x=1#top", "/more", Some("y=2&z=%20")),
+            "https://e.example/p/more?x=1&y=2&z=%20#top");
+        assert_eq!(forward_url("https://e.example/p#top", "", Some("q=a b")), "https://e.example/p?q=a%20b#top");
     }
 }
@@ -0,0 +1,343 @@
+use std::env;
+use std::fs;
+
+use crate::log;
+use crate::normalize::Normalization;
+use crate::server;
+use crate::store::Redirect;
+use crate::token;
+#[cfg(feature = "tls")]
+use crate::tls;
+
+
+/// Everything that can be configured, independent of where the values come from.
+pub struct Settings {
+    pub mappings: Option<String>,
+    pub addr: String,
+    pub hostnames: Vec<String>,
+    pub includes: Vec<String>,
+    pub workers: usize,
+    pub redirect: Redirect,
+    pub cache_control: Option<String>,
+    pub templates: Option<String>,
+    pub reload_max_shrink: usize,
+    pub normalization: Normalization,
+    pub auto_redirect: server::AutoRedirect,
+    pub token_length: usize,
+    pub token_alphabet: token::Alphabet,
+    pub token_strategy: token::Strategy,
+    pub access_log: Option<String>,
+    pub log_format: log::Format,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_sni: Vec<(String, String, String)>,
+    pub api_secret_file: Option<String>,
+}
+
+/// Description of a setting for parsing and help texts.
+pub struct Setting {
+    pub name: &'static str,
+    pub short: Option<char>,
+    pub value: &'static str,
+    pub help: &'static str,
+}
+
+/// A value for a setting and where it was given, for error messages.
+pub struct Assignment {
+    pub name: String,
+    pub value: String,
+    pub origin: String,
+}
+
+
+pub const SETTINGS: &[Setting] = &[
+    Setting { name: "mappings", short: Some('m'), value: "<path>", help: "mapping file or directory, commands then take their arguments without it" },
+    Setting { name: "addr", short: Some('a'), value: "<address>", help: "address to listen on (default localhost:8080)" },
+    Setting { name: "hostname", short: None, value: "<name>[,<name>...]", help: "public host names of this instance, used by check to find redirect loops" },
+    Setting { name: "include", short: Some('i'), value: "<path>", help: "additional mapping file or pattern like links/*.txt, may be repeated" },
+    Setting { name: "workers", short: Some('w'), value: "<n>", help: "number of worker threads (default 8)" },
+    Setting { name: "redirect", short: Some('r'), value: "<status>", help: "default redirect status: 301, 302, 303, 307 or 308 (default 308)" },
+    Setting { name: "cache-control", short: None, value: "<value>", help: "Cache-Control header sent with redirects" },
+    Setting { name: "templates", short: None, value: "<dir>", help: "directory with index.html, 404.html, redirect.html or style.css to use instead of the built-in ones" },
+    Setting { name: "reload-max-shrink", short: None, value: "<percent>", help: "reject reloads of the mapping file that drop more links than this, 100 to allow all (default 50)" },
+    Setting { name: "normalize", short: None, value: "<rule>[,<rule>...]", help: "how requested and stored tokens are normalized: trailing-slash, case, nfc or none (default trailing-slash)" },
+    Setting { name: "auto-redirect", short: None, value: "<rule>[,<rule>...]", help: "redirect unknown tokens to the only token matching them: case, prefix or none (default none)" },
+    Setting { name: "token-length", short: None, value: "<n>", help: "minimum length of generated tokens (default 5)" },
+    Setting { name: "token-alphabet", short: None, value: "<name>", help: "friendly, lowercase or base62 (default friendly)" },
+    Setting { name: "token-strategy", short: None, value: "<name>", help: "random, sequential or hash (default random)" },
+    Setting { name: "access-log", short: None, value: "<path>", help: "file to log requests to, - for stdout" },
+    Setting { name: "log-format", short: None, value: "<name>", help: "combined or json (default combined)" },
+    Setting { name: "tls-cert", short: None, value: "<path>", help: "PEM certificate chain to serve HTTPS with" },
+    Setting { name: "tls-key", short: None, value: "<path>", help: "PEM private key for --tls-cert" },
+    Setting { name: "tls-sni", short: None, value: "<name>=<cert>,<key>", help: "additional certificate for a server name, may be repeated" },
+    Setting { name: "api-secret-file", short: None, value: "<path>", help: "file containing the secret for the HTTP API (default $LISHO_API_SECRET)" },
+];
+
+const ENV_PREFIX: &str = "LISHO_";
+/// Variables that are not settings but still belong to lisho.
+const ENV_OTHERS: &[&str] = &["LISHO_API_SECRET", "LISHO_CONFIG"];
+const DEFAULT_ADDR: &str = "localhost:8080";
+const DEFAULT_WORKERS: usize = 8;
+const DEFAULT_RELOAD_MAX_SHRINK: usize = 50;
+const DEFAULT_TOKEN_LENGTH: usize = 5;
+
+
+impl Settings {
+    /// Changes a setting by its name as listed in `SETTINGS`.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "mappings" => self.mappings = Some(value.to_owned()).filter(|v| !v.is_empty()),
+            "addr" => self.addr = value.to_owned(),
+            "hostname" => self.hostnames = value.split(',').map(str::trim)
+                .filter(|h| !h.is_empty()).map(str::to_owned).collect(),
+            "include" => self.includes.push(value.to_owned()),
+            "workers" => self.workers = parse_positive(value, "Number of workers")?,
+            "redirect" => self.redirect = Redirect::from_code(value)
+                .ok_or("Redirect status must be one of 301, 302, 303, 307 or 308")?,
+            "cache-control" => self.cache_control = Some(value.to_owned()).filter(|v| !v.is_empty()),
+            "templates" => self.templates = Some(value.to_owned()),
+            "reload-max-shrink" => self.reload_max_shrink = match value.parse() {
+                Ok(percent) if percent <= 100 => percent,
+                _ => return Err("Maximum shrink must be a percentage from 0 to 100".to_owned()),
+            },
+            "normalize" => self.normalization = Normalization::from_names(value)
+                .ok_or("Normalization rules must be trailing-slash, case, nfc or none")?,
+            "auto-redirect" => self.auto_redirect = server::AutoRedirect::from_names(value)
+                .ok_or("Auto-redirect rules must be case, prefix or none")?,
+            "token-length" => self.token_length = parse_positive(value, "Token length")?,
+            "token-alphabet" => self.token_alphabet = token::Alphabet::from_name(value)
+                .ok_or("Token alphabet must be one of friendly, lowercase or base62")?,
+            "token-strategy" => self.token_strategy = token::Strategy::from_name(value)
+                .ok_or("Token strategy must be one of random, sequential or hash")?,
+            "access-log" => self.access_log = Some(value.to_owned()),
+            "log-format" => self.log_format = log::Format::from_name(value)
+                .ok_or("Log format must be one of combined or json")?,
+            "tls-cert" => self.tls_cert = Some(value.to_owned()),
+            "tls-key" => self.tls_key = Some(value.to_owned()),
+            "tls-sni" => {
+                let (name, paths) = value.split_once('=')
+                    .ok_or("SNI certificates must be given as <name>=<cert>,<key>")?;
+                let (cert, key) = paths.split_once(',')
+                    .ok_or("SNI certificates must be given as <name>=<cert>,<key>")?;
+                self.tls_sni.push((name.to_owned(), cert.to_owned(), key.to_owned()));
+            },
+            "api-secret-file" => self.api_secret_file = Some(value.to_owned()),
+            _ => return Err(format!("Unknown setting '{name}'")),
+        }
+
+        Ok(())
+    }
+
+    /// Applies the assignments of one source, replacing what lower priority sources set.
+    pub fn apply(&mut self, assignments: &[Assignment]) -> Result<(), String> {
+        // lists are not merged across sources
+        if assignments.iter().any(|a| a.name == "tls-sni") {
+            self.tls_sni.clear();
+        }
+        if assignments.iter().any(|a| a.name == "include") {
+            self.includes.clear();
+        }
+
+        for assignment in assignments {
+            self.set(&assignment.name, &assignment.value)
+                .map_err(|e| format!("{}: {e}", assignment.origin))?;
+        }
+        Ok(())
+    }
+
+    /// Checks settings that only make sense together and files that have to exist.
+    pub fn check(&self) -> Result<(), String> {
+        if self.tls_enabled() && !cfg!(feature = "tls") {
+            return Err("This build of lisho does not support TLS, rebuild it with `--features tls`".to_owned());
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err("tls-cert and tls-key have to be given together".to_owned());
+        }
+        if let Some(dir) = &self.templates {
+            fs::read_dir(dir).map_err(|e| format!("Unable to read templates in {dir}: {e}"))?;
+        }
+        if let Some(path) = &self.api_secret_file {
+            fs::metadata(path).map_err(|e| format!("Unable to read API secret from {path}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// The secret for the HTTP API, which is disabled without one.
+    pub fn api_secret(&self) -> Result<Option<String>, String> {
+        let secret = match &self.api_secret_file {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read API secret from {path}: {e}"))?
+                .trim_end().to_owned(),
+            None => env::var("LISHO_API_SECRET").unwrap_or_default(),
+        };
+        Ok(Some(secret).filter(|s| !s.is_empty()))
+    }
+
+    pub fn generator(&self) -> token::Generator {
+        token::Generator {
+            length: self.token_length,
+            alphabet: self.token_alphabet,
+            strategy: self.token_strategy,
+        }
+    }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some() || self.tls_key.is_some() || !self.tls_sni.is_empty()
+    }
+
+    /// Opens files and loads certificates needed by the server.
+    pub fn server_options(&self) -> Result<server::Options, String> {
+        let access_log = match &self.access_log {
+            Some(path) => Some(log::AccessLog::open(path, self.log_format)
+                .map_err(|e| format!("Unable to open access log: {e}"))?),
+            None => None,
+        };
+        let pages = server::Pages::load(self.templates.as_deref())
+            .map_err(|e| format!("Unable to load templates: {e}"))?;
+
+        Ok(server::Options {
+            workers: self.workers,
+            redirect: self.redirect,
+            cache_control: self.cache_control.clone(),
+            pages,
+            max_shrink: self.reload_max_shrink,
+            auto_redirect: self.auto_redirect,
+            api_secret: self.api_secret()?,
+            generator: self.generator(),
+            access_log,
+            #[cfg(feature = "tls")]
+            tls: self.tls_acceptor()?,
+        })
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_acceptor(&self) -> Result<Option<tls::Acceptor>, String> {
+        if !self.tls_enabled() {
+            return Ok(None);
+        }
+
+        let mut sources = Vec::new();
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert_path), Some(key_path)) => sources.push(tls::CertSource {
+                server_name: None,
+                cert_path: cert_path.clone(),
+                key_path: key_path.clone(),
+            }),
+            (None, None) => (),
+            _ => return Err("tls-cert and tls-key have to be given together".to_owned()),
+        }
+        for (name, cert_path, key_path) in &self.tls_sni {
+            sources.push(tls::CertSource {
+                server_name: Some(name.clone()),
+                cert_path: cert_path.clone(),
+                key_path: key_path.clone(),
+            });
+        }
+
+        tls::Acceptor::new(sources)
+            .map(Some)
+            .map_err(|e| format!("Unable to load certificates: {e}"))
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            mappings: None,
+            addr: DEFAULT_ADDR.to_owned(),
+            hostnames: Vec::new(),
+            includes: Vec::new(),
+            workers: DEFAULT_WORKERS,
+            redirect: Redirect::Permanent,
+            cache_control: None,
+            templates: None,
+            reload_max_shrink: DEFAULT_RELOAD_MAX_SHRINK,
+            normalization: Normalization { trailing_slash: true, ..Normalization::default() },
+            auto_redirect: server::AutoRedirect::default(),
+            token_length: DEFAULT_TOKEN_LENGTH,
+            token_alphabet: token::Alphabet::Friendly,
+            token_strategy: token::Strategy::Random,
+            access_log: None,
+            log_format: log::Format::Combined,
+            tls_cert: None,
+            tls_key: None,
+            tls_sni: Vec::new(),
+            api_secret_file: None,
+        }
+    }
+}
+
+
+fn parse_positive(value: &str, what: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("{what} must be a positive integer")),
+    }
+}
+
+/// Collects settings from `LISHO_*` variables, e.g. `LISHO_TOKEN_LENGTH` for `token-length`.
+pub fn read_env() -> Vec<Assignment> {
+    let mut assignments = Vec::new();
+    for (key, value) in env::vars() {
+        let Some(name) = key.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let name = name.to_lowercase().replace('_', "-");
+        if !SETTINGS.iter().any(|s| s.name == name) {
+            if !ENV_OTHERS.contains(&key.as_str()) {
+                eprintln!("Ignoring unknown setting {key}");
+            }
+            continue;
+        }
+
+        // a variable can only be given once, so lists are separated by whitespace
+        let values: Vec<_> = match name.as_str() {
+            "tls-sni" | "include" => value.split_whitespace().collect(),
+            _ => vec![value.as_str()],
+        };
+        for value in values {
+            assignments.push(Assignment { name: name.clone(), value: value.to_owned(), origin: key.clone() });
+        }
+    }
+
+    assignments
+}
+
+/// Reads a config file with one `name = value` per line.
+///
+/// Names are those of `SETTINGS`, `[section]` headers prefix the names that follow
+/// so `cert` in `[tls]` is the same as `tls-cert`.
+pub fn read_config(path: &str) -> Result<Vec<Assignment>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read config {path}: {e}"))?;
+
+    let mut assignments = Vec::new();
+    let mut section = String::new();
+    for (i, line) in contents.lines().enumerate() {
+        let origin = format!("{path}:{}", i + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().replace('_', "-");
+            continue;
+        }
+
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(format!("{origin}: Expected 'name = value'"));
+        };
+        let name = name.trim().replace('_', "-");
+        let name = if section.is_empty() { name } else { format!("{section}-{name}") };
+        if !SETTINGS.iter().any(|s| s.name == name) {
+            return Err(format!("{origin}: Unknown setting '{name}'"));
+        }
+
+        let value = value.trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        assignments.push(Assignment { name, value: value.to_owned(), origin });
+    }
+
+    Ok(assignments)
+}
@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+
+static HANGUPS: AtomicUsize = AtomicUsize::new(0);
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+
+/// Starts counting SIGHUPs and turns SIGTERM and SIGINT into a request to shut down,
+/// instead of letting them terminate the process.
+///
+/// A second SIGTERM or SIGINT exits right away, in case shutting down takes too long.
+#[cfg(unix)]
+pub fn install() {
+    use std::os::raw::c_int;
+
+    const SIGHUP: c_int = 1;
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+        fn _exit(status: c_int) -> !;
+    }
+
+    extern "C" fn on_hangup(_: c_int) {
+        HANGUPS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    extern "C" fn on_terminate(signum: c_int) {
+        if SHUTDOWN.swap(true, Ordering::SeqCst) {
+            unsafe { _exit(128 + signum) };
+        }
+    }
+
+    unsafe {
+        signal(SIGHUP, on_hangup as extern "C" fn(c_int) as usize);
+        signal(SIGINT, on_terminate as extern "C" fn(c_int) as usize);
+        signal(SIGTERM, on_terminate as extern "C" fn(c_int) as usize);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+/// Number of SIGHUPs received so far.
+///
+/// Each user remembers the last value it has seen, so every one of them notices a new signal.
+pub fn hangups() -> usize {
+    HANGUPS.load(Ordering::SeqCst)
+}
+
+/// Whether SIGTERM or SIGINT has been received.
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
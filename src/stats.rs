@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::request::percent_decode;
+
+
+/// Access counters for links and for tokens that could not be found.
+pub struct Stats {
+    hits: HashMap<String, Hits>,
+    misses: HashMap<String, u64>,
+    total_misses: u64,
+    dirty: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct Hits {
+    pub count: u64,
+    pub last_access: SystemTime,
+}
+
+
+/// Keeps scanners from filling up memory with random tokens.
+const MAX_TRACKED_MISSES: usize = 1000;
+const STATS_PAGE: &str = include_str!("stats.html");
+
+
+impl Stats {
+    /// Reads statistics persisted by `save()`, starting fresh if there are none yet.
+    pub fn load(file_path: &str) -> io::Result<Self> {
+        let mut stats = Stats {
+            hits: HashMap::new(),
+            misses: HashMap::new(),
+            total_misses: 0,
+            dirty: false,
+        };
+
+        let file_contents = match fs::read_to_string(file_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(stats),
+            Err(e) => return Err(e),
+        };
+
+        for line in file_contents.lines() {
+            let fields: Vec<_> = line.split('\t').collect();
+            match fields[..] {
+                ["hit", count, last_access, token] => {
+                    let (Ok(count), Ok(secs), Some(token)) = (count.parse(), last_access.parse(), percent_decode(token, false)) else {
+                        continue;
+                    };
+                    let last_access = UNIX_EPOCH + Duration::from_secs(secs);
+                    stats.hits.insert(token, Hits { count, last_access });
+                },
+                ["miss", count, token] => {
+                    if let (Ok(count), Some(token)) = (count.parse(), percent_decode(token, false)) {
+                        stats.misses.insert(token, count);
+                    }
+                },
+                ["total-misses", count] => stats.total_misses = count.parse().unwrap_or(0),
+                _ => eprintln!("Invalid stats entry '{line}'"),
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Writes the statistics to `file_path` if they changed since the last save.
+    pub fn save(&mut self, file_path: &str) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let mut contents = format!("total-misses\t{}\n", self.total_misses);
+        for (token, hits) in &self.hits {
+            let secs = hits.last_access.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            contents.push_str(&format!("hit\t{}\t{secs}\t{}\n", hits.count, escape_token(token)));
+        }
+        for (token, count) in &self.misses {
+            contents.push_str(&format!("miss\t{count}\t{}\n", escape_token(token)));
+        }
+
+        let tmp_path = format!("{file_path}.tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, file_path)?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub fn hit(&mut self, token: &str) {
+        let hits = self.hits.entry(token.to_owned())
+            .or_insert(Hits { count: 0, last_access: UNIX_EPOCH });
+        hits.count += 1;
+        hits.last_access = SystemTime::now();
+        self.dirty = true;
+    }
+
+    pub fn miss(&mut self, token: &str) {
+        self.total_misses += 1;
+        if let Some(count) = self.misses.get_mut(token) {
+            *count += 1;
+        } else if self.misses.len() < MAX_TRACKED_MISSES {
+            self.misses.insert(token.to_owned(), 1);
+        }
+        self.dirty = true;
+    }
+
+    /// Hits for each of `tokens`, most requested first, including links that were never used.
+    fn links<'a>(&self, tokens: impl Iterator<Item = &'a str>) -> Vec<(&'a str, Option<Hits>)> {
+        let mut links: Vec<_> = tokens.map(|t| (t, self.hits.get(t).copied())).collect();
+        links.sort_by(|(t1, h1), (t2, h2)| {
+            let (c1, c2) = (h1.map(|h| h.count), h2.map(|h| h.count));
+            c2.cmp(&c1).then(t1.cmp(t2))
+        });
+        links
+    }
+
+    fn misses(&self) -> Vec<(&str, u64)> {
+        let mut misses: Vec<_> = self.misses.iter().map(|(t, c)| (t.as_str(), *c)).collect();
+        misses.sort_by(|(t1, c1), (t2, c2)| c2.cmp(c1).then(t1.cmp(t2)));
+        misses
+    }
+
+    pub fn to_json<'a>(&self, tokens: impl Iterator<Item = &'a str>) -> String {
+        let links: Vec<_> = self.links(tokens).into_iter()
+            .map(|(token, hits)| {
+                let (count, last_access) = match hits {
+                    Some(hits) => (hits.count, unix_secs(hits.last_access).to_string()),
+                    None => (0, "null".to_owned()),
+                };
+                format!("{{\"token\":{},\"hits\":{count},\"last_access\":{last_access}}}", json_string(token))
+            })
+            .collect();
+        let misses: Vec<_> = self.misses().into_iter()
+            .map(|(token, count)| format!("{{\"token\":{},\"count\":{count}}}", json_string(token)))
+            .collect();
+
+        format!("{{\"links\":[{}],\"misses\":[{}],\"total_misses\":{}}}\n",
+            links.join(","), misses.join(","), self.total_misses)
+    }
+
+    pub fn to_html<'a>(&self, tokens: impl Iterator<Item = &'a str>) -> String {
+        let links: String = self.links(tokens).into_iter()
+            .map(|(token, hits)| {
+                let (count, last_access) = match hits {
+                    Some(hits) => (hits.count, format!("{} s ago", SystemTime::now()
+                        .duration_since(hits.last_access).unwrap_or_default().as_secs())),
+                    None => (0, "never".to_owned()),
+                };
+                format!("<tr><td>/{}</td><td>{count}</td><td>{last_access}</td></tr>\n", html_escape(token))
+            })
+            .collect();
+        let misses: String = self.misses().into_iter()
+            .map(|(token, count)| format!("<tr><td>/{}</td><td>{count}</td></tr>\n", html_escape(token)))
+            .collect();
+
+        STATS_PAGE.replace("STATS_LINKS", &links)
+            .replace("STATS_MISSES", &misses)
+            .replace("STATS_TOTAL_MISSES", &self.total_misses.to_string())
+    }
+}
+
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Keeps tokens of 404s, which may contain anything, on a single field.
+fn escape_token(token: &str) -> String {
+    token.replace('%', "%25").replace('\t', "%09").replace('\n', "%0A").replace('\r', "%0D")
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Replaces the characters that have a meaning in HTML.
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -1,68 +1,994 @@
+use std::fmt;
 use std::fs;
 use std::io;
+use std::io::Write;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
+use crate::normalize::Normalization;
+use crate::request::percent_encode;
+use crate::time::Timestamp;
+
 
 pub struct Store {
     file_path: String,
-    map: HashMap<String, String>,
-    last_modified: SystemTime,
+    includes: Vec<String>,
+    normalization: Normalization,
+    map: HashMap<String, Link>,
+    /// The files defining each token and how it is written there, the effective definition last.
+    origins: HashMap<String, Vec<(PathBuf, String)>>,
+    /// The namespace of every file that is not part of the root namespace.
+    namespaces: HashMap<PathBuf, String>,
+    version: Version,
+    globs: Vec<PathBuf>,
+}
+
+/// A mapping file and everything it includes, in the order the mappings take effect.
+pub struct Files {
+    pub sources: Vec<Source>,
+    pub definitions: Vec<Definition>,
+    /// Problems with include directives as index into `sources`, line number and message.
+    pub errors: Vec<(usize, usize, String)>,
+    normalization: Normalization,
+    globs: Vec<PathBuf>,
+}
+
+pub struct Source {
+    pub path: PathBuf,
+    /// Prefix of the tokens in this file, when serving a directory.
+    pub namespace: Option<String>,
+    pub document: Document,
+    last_modified: Option<SystemTime>,
+}
+
+/// A mapping as found in one of the `Files`.
+pub struct Definition {
+    pub source: usize,
+    pub line: usize,
+    /// The normalized token including its namespace.
+    pub token: String,
+    /// The token as written in the file.
+    pub written: String,
+    pub link: Link,
 }
 
+/// Modification times of all files a store was read from, to tell whether any of them changed.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Version(Vec<(PathBuf, Option<SystemTime>)>);
+
+/// A mapping file as a human wrote it.
+///
+/// Comments, blank lines, ordering and fields the store does not know about are kept,
+/// only lines touched by an edit are changed.
+pub struct Document {
+    lines: Vec<String>,
+    trailing_newline: bool,
+}
+
+#[derive(Clone)]
+pub struct Link {
+    pub url: String,
+    pub redirect: Option<Redirect>,
+    /// Whether the rest of the request path and the query are appended to the URL.
+    pub forward: bool,
+    /// Used instead of a template URL if arguments for its placeholders are missing.
+    pub default: Option<String>,
+    /// From then on the link is gone.
+    pub expires: Option<Timestamp>,
+    /// Before then the link does not exist yet.
+    pub not_before: Option<Timestamp>,
+}
+
+#[derive(Clone, Copy)]
+pub enum Redirect {
+    MovedPermanently,
+    Found,
+    SeeOther,
+    Temporary,
+    Permanent,
+}
+
+
+/// In a mapping directory, the file with the tokens that are not in a namespace.
+pub const ROOT_FILE: &str = "_root.txt";
+
 
 impl Store {
-    pub fn new(file_path: &str) -> io::Result<Self> {
-        Ok(Store {
+    /// Reads `file_path` with the files it includes, followed by the files in `includes`.
+    ///
+    /// If `file_path` is a directory, each file in it is a namespace, see `Files::read()`.
+    /// Tokens are looked up after applying `normalization` to them.
+    pub fn new(file_path: &str, includes: &[String], normalization: Normalization) -> io::Result<Self> {
+        let files = Files::read(file_path, includes, normalization)?;
+        for source in &files.sources {
+            for (line_number, error) in source.document.errors() {
+                eprintln!("{}:{line_number}: {error}", source.path.display());
+            }
+        }
+        for (source, line_number, error) in &files.errors {
+            eprintln!("{}:{line_number}: {error}", files.sources[*source].path.display());
+        }
+
+        Ok(Self::from_files(file_path, includes, files))
+    }
+
+    fn from_files(file_path: &str, includes: &[String], files: Files) -> Self {
+        let normalization = files.normalization;
+        let mut map = HashMap::new();
+        let mut origins = HashMap::new();
+        let namespaces = files.sources.iter()
+            .filter_map(|s| s.namespace.clone().map(|namespace| (s.path.clone(), namespace)))
+            .collect();
+        for definition in files.definitions {
+            let path = files.sources[definition.source].path.clone();
+            origins.entry(definition.token.clone()).or_insert_with(Vec::new).push((path, definition.written));
+            map.insert(definition.token, definition.link);
+        }
+
+        Store {
             file_path: file_path.to_owned(),
-            map: read_mappings(file_path)?,
-            last_modified: fs::metadata(file_path)?.modified()?,
-        })
+            includes: includes.to_vec(),
+            normalization,
+            map,
+            origins,
+            namespaces,
+            version: Version(files.sources.iter().map(|s| (s.path.clone(), s.last_modified)).collect()),
+            globs: files.globs,
+        }
+    }
+
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    /// The version of the files as they are now, which may be newer than the loaded links.
+    ///
+    /// New files matching an include pattern count as a change as well.
+    pub fn disk_version(&self) -> Version {
+        let mut version: Vec<_> = self.version.0.iter()
+            .map(|(path, _)| (path.clone(), last_modified(path).ok()))
+            .collect();
+        for path in self.globs.iter().flat_map(|g| expand_glob(g)) {
+            if !version.iter().any(|(p, _)| *p == path) {
+                let last_modified = last_modified(&path).ok();
+                version.push((path, last_modified));
+            }
+        }
+        Version(version)
     }
 
-    pub fn has_changed(&self) -> io::Result<bool> {
-        let file_last_modified = last_modified(&self.file_path)?;
-        Ok(self.last_modified != file_last_modified)
+    /// Reads the mapping files again, leaving the current store untouched.
+    pub fn reload(&self) -> io::Result<Self> {
+        Self::new(&self.file_path, &self.includes, self.normalization)
     }
 
-    pub fn refresh(&mut self) -> io::Result<()> {
-        self.last_modified = last_modified(&self.file_path)?;
-        self.map = read_mappings(&self.file_path)?;
+    /// Like `reload()`, but fails if any line is not a valid mapping or an include is broken.
+    ///
+    /// Meant for files edited while being served, which might be saved halfway.
+    pub fn reload_strict(&self) -> Result<Self, String> {
+        let files = Files::read(&self.file_path, &self.includes, self.normalization).map_err(|e| e.to_string())?;
+        for source in &files.sources {
+            if let Some((line_number, error)) = source.document.errors().into_iter().next() {
+                return Err(format!("{}:{line_number}: {error}", source.path.display()));
+            }
+        }
+        if let Some((source, line_number, error)) = files.errors.first() {
+            return Err(format!("{}:{line_number}: {error}", files.sources[*source].path.display()));
+        }
+
+        Ok(Self::from_files(&self.file_path, &self.includes, files))
+    }
+
+    /// Adds or replaces the link for `token` and reloads the store.
+    ///
+    /// Existing links are changed in the file they come from, new ones are added to the main file
+    /// or the file of their namespace.
+    pub fn set(&mut self, token: &str, link: &Link) -> io::Result<()> {
+        let token = self.normalization.apply(token);
+        let (path, written) = match (self.origins.get(&token).and_then(|o| o.last()), self.namespace(&token)) {
+            (Some((path, written)), _) => (path.clone(), written.clone()),
+            (None, Some(namespace)) => {
+                let local_token = token.split_once('/').map_or("", |(_, local)| local).to_owned();
+                (Path::new(&self.file_path).join(format!("{namespace}.txt")), local_token)
+            },
+            (None, None) => (self.main_file(), token.clone()),
+        };
+
+        let mut document = match Document::open(&path) {
+            // a mapping directory does not need a root file until there are links for it
+            Err(e) if e.kind() == io::ErrorKind::NotFound && self.is_directory() => Document::parse(""),
+            result => result?,
+        };
+        if !document.update(&written, link) && !document.insert(&written, link) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("'{written} {}' would not be read as a mapping", link.url)));
+        }
+
+        document.save(&path)?;
+        *self = self.reload()?;
         Ok(())
     }
 
-    pub fn get(&self, key: &str) -> Option<&str> {
-        self.map.get(key).map(|x| x.as_str())
+    /// Removes the link for `token` from all files and reloads the store.
+    ///
+    /// Returns `false` if there was no such link.
+    pub fn remove(&mut self, token: &str) -> io::Result<bool> {
+        let mut removed = false;
+        let origins = self.origins.get(&self.normalization.apply(token)).cloned().unwrap_or_default();
+        for (path, written) in &origins {
+            let mut document = Document::open(path)?;
+            if document.remove(written) {
+                document.save(path)?;
+                removed = true;
+            }
+        }
+
+        if removed {
+            *self = self.reload()?;
+        }
+        Ok(removed)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Link> {
+        self.map.get(&self.normalize(key))
+    }
+
+    /// Existing tokens `token` might be a typo or the beginning of, the most similar first.
+    pub fn similar(&self, token: &str, max: usize) -> Vec<&str> {
+        let token = self.normalize(token);
+        let threshold = (token.chars().count() / 3).max(1);
+
+        let mut similar: Vec<_> = self.active_tokens()
+            .filter(|t| !t.is_empty())
+            .map(|t| (edit_distance(&token, t), t))
+            .filter(|&(distance, t)| distance <= threshold || (!token.is_empty() && t.starts_with(token.as_str())))
+            .collect();
+        similar.sort();
+        similar.into_iter().take(max).map(|(_, t)| t).collect()
+    }
+
+    /// Existing tokens that are the same as `token` when ignoring case.
+    pub fn case_insensitive_matches(&self, token: &str) -> Vec<&str> {
+        let token = self.normalize(token).to_lowercase();
+        self.active_tokens().filter(|t| t.to_lowercase() == token).collect()
+    }
+
+    /// Existing tokens starting with `token`.
+    pub fn prefix_matches(&self, token: &str) -> Vec<&str> {
+        let token = self.normalize(token);
+        self.active_tokens().filter(|t| t.starts_with(token.as_str())).collect()
+    }
+
+    /// Tokens of the links that are neither expired nor pending.
+    fn active_tokens(&self) -> impl Iterator<Item = &str> {
+        let now = SystemTime::now();
+        self.map.iter()
+            .filter(move |(_, link)| !link.is_expired(now) && !link.is_pending(now))
+            .map(|(token, _)| token.as_str())
+    }
+
+    /// `token` as it is used for lookups and statistics.
+    pub fn normalize(&self, token: &str) -> String {
+        self.normalization.apply(token)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Link)> {
+        self.map.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    pub fn tokens(&self) -> impl Iterator<Item = &str> {
+        self.map.keys().map(|k| k.as_str())
+    }
+
+    /// The main mapping file or directory.
+    pub fn file_path(&self) -> &str {
+        &self.file_path
+    }
+
+    /// The namespace `token` would belong to, if there is a file for it.
+    pub fn namespace(&self, token: &str) -> Option<&str> {
+        let token = self.normalization.apply(token);
+        let prefix = token.split_once('/').map_or(token.as_str(), |(prefix, _)| prefix);
+        self.namespaces.values().find(|n| self.normalization.apply(n) == prefix).map(|n| n.as_str())
+    }
+
+    /// Files and directories whose changes have to be picked up by `reload()`.
+    pub fn watched_paths(&self) -> Vec<PathBuf> {
+        let files = self.version.0.iter().map(|(path, _)| path.clone());
+        let glob_dirs = self.globs.iter().map(|g| g.parent().unwrap_or(Path::new(".")).to_owned());
+        files.chain(glob_dirs).collect()
     }
 
     pub fn len(&self) -> usize {
         self.map.len()
     }
+
+    fn is_directory(&self) -> bool {
+        Path::new(&self.file_path).is_dir()
+    }
+
+    /// The file new links without a namespace are added to.
+    fn main_file(&self) -> PathBuf {
+        if self.is_directory() {
+            Path::new(&self.file_path).join(ROOT_FILE)
+        } else {
+            PathBuf::from(&self.file_path)
+        }
+    }
+}
+
+impl Files {
+    /// Reads `file_path`, the files included from it and `includes`.
+    ///
+    /// Only unreadable files given here are an error, problems with files included from
+    /// them end up in `errors`.
+    /// A directory is read as mapping directory: each `<namespace>.txt` in it holds the
+    /// links below `/<namespace>/`, except for `_root.txt` with the links without a namespace.
+    pub fn read(file_path: &str, includes: &[String], normalization: Normalization) -> io::Result<Self> {
+        let mut files = Files {
+            sources: Vec::new(),
+            definitions: Vec::new(),
+            errors: Vec::new(),
+            normalization,
+            globs: Vec::new(),
+        };
+        let path = Path::new(file_path);
+        if path.is_dir() {
+            files.read_directory(path)?;
+        } else {
+            files.read_file(path, None, &mut Vec::new())?;
+        }
+
+        // extra files behave as if they were included at the end of the main file
+        for include in includes {
+            let include = Path::new(include);
+            if is_glob(include) {
+                files.globs.push(include.to_owned());
+                for path in expand_glob(include) {
+                    files.read_file(&path, None, &mut Vec::new())?;
+                }
+            } else {
+                files.read_file(include, None, &mut Vec::new())?;
+            }
+        }
+        Ok(files)
+    }
+
+    fn read_directory(&mut self, dir: &Path) -> io::Result<()> {
+        let root = dir.join(ROOT_FILE);
+        if root.is_file() {
+            self.read_file(&root, None, &mut Vec::new())?;
+        }
+
+        // the pattern makes new namespaces count as a change
+        let pattern = dir.join("*.txt");
+        for path in expand_glob(&pattern).into_iter().filter(|p| *p != root) {
+            let namespace = path.file_stem().map(|s| s.to_string_lossy().into_owned());
+            if namespace.as_deref().is_some_and(is_valid_token) {
+                self.read_file(&path, namespace, &mut Vec::new())?;
+            }
+        }
+        self.globs.push(pattern);
+        Ok(())
+    }
+
+    /// Reads the mappings in `path` and the files it includes, which share its namespace.
+    fn read_file(&mut self, path: &Path, namespace: Option<String>, stack: &mut Vec<PathBuf>) -> io::Result<()> {
+        // edits made while reading will show up as a newer `disk_version()`
+        let last_modified = last_modified(path)?;
+        let document = Document::open(path)?;
+        let source = self.sources.len();
+
+        stack.push(fs::canonicalize(path)?);
+        let mut entries: Vec<_> = document.mappings()
+            .filter_map(|(line, _, token, fields)| fields.split_first()
+                .map(|(url, attributes)| (line, Some((token.to_owned(), parse_link(url, attributes).0)), None)))
+            .chain(document.includes().map(|(line, target)| (line, None, Some(target.to_owned()))))
+            .collect();
+        entries.sort_by_key(|(line, _, _)| *line);
+
+        self.sources.push(Source {
+            path: path.to_owned(),
+            namespace: namespace.clone(),
+            document,
+            last_modified: Some(last_modified),
+        });
+        for (line, mapping, include) in entries {
+            if let Some((written, link)) = mapping {
+                let token = match &namespace {
+                    Some(namespace) if written.is_empty() => namespace.clone(),
+                    Some(namespace) => format!("{namespace}/{written}"),
+                    None => written.clone(),
+                };
+                let token = self.normalization.apply(&token);
+                self.definitions.push(Definition { source, line, token, written, link });
+            }
+            if let Some(target) = include {
+                let target = path.parent().unwrap_or(Path::new("")).join(target);
+                self.include(source, line, &target, namespace.clone(), stack);
+            }
+        }
+        stack.pop();
+        Ok(())
+    }
+
+    /// Reads the files matching `target`, which was included on `line` of `source`.
+    fn include(&mut self, source: usize, line: usize, target: &Path, namespace: Option<String>,
+               stack: &mut Vec<PathBuf>) {
+        let paths = if is_glob(target) {
+            self.globs.push(target.to_owned());
+            expand_glob(target)
+        } else {
+            vec![target.to_owned()]
+        };
+
+        for path in paths {
+            let cycle = fs::canonicalize(&path).ok()
+                .and_then(|canonical| stack.iter().position(|p| *p == canonical));
+            if let Some(start) = cycle {
+                let chain: Vec<_> = stack[start..].iter().chain([&stack[start]])
+                    .map(|p| p.display().to_string()).collect();
+                self.errors.push((source, line, format!("Include cycle {}", chain.join(" -> "))));
+                continue;
+            }
+
+            if let Err(e) = self.read_file(&path, namespace.clone(), stack) {
+                self.errors.push((source, line, format!("Unable to include {}: {e}", path.display())));
+            }
+        }
+    }
+}
+
+impl Document {
+    pub fn open(file_path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(file_path)?))
+    }
+
+    pub fn parse(contents: &str) -> Self {
+        Document {
+            lines: contents.lines().map(str::to_owned).collect(),
+            trailing_newline: contents.ends_with('\n') || contents.is_empty(),
+        }
+    }
+
+    /// Line numbers, lines, tokens and fields of everything that is not a comment.
+    ///
+    /// Lines without any fields after the token are included, although they are not valid mappings.
+    pub fn mappings(&self) -> impl Iterator<Item = (usize, &str, &str, Vec<&str>)> {
+        self.lines.iter().enumerate()
+            .filter_map(|(i, line)| split_line(line).map(|(token, fields)| (i + 1, line.as_str(), token, fields)))
+    }
+
+    /// Line numbers and targets of `include` directives.
+    pub fn includes(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.lines.iter().enumerate()
+            .filter_map(|(i, line)| include_target(line).map(|target| (i + 1, target)))
+    }
+
+    /// Lines that are not valid mappings or have invalid attributes, with their line numbers.
+    pub fn errors(&self) -> Vec<(usize, String)> {
+        let mut errors = Vec::new();
+        for (line_number, _, _, fields) in self.mappings() {
+            match fields.split_first() {
+                Some((url, attributes)) => errors.extend(parse_link(url, attributes).1.into_iter()
+                    .map(|e| (line_number, e))),
+                None => errors.push((line_number, "Invalid mapping, the URL is missing".to_owned())),
+            }
+        }
+        errors
+    }
+
+    /// Appends a mapping for `token`, unless there already is one or the line would not be read
+    /// back as a mapping, like `include` followed by a path.
+    pub fn insert(&mut self, token: &str, link: &Link) -> bool {
+        if self.find(token).is_some() {
+            return false;
+        }
+
+        let mut line = format!("{token} {}", link.url);
+        for (key, value) in link.attributes() {
+            if let Some(value) = value {
+                line.push_str(&format!(" {key}={value}"));
+            }
+        }
+        if !is_mapping_for(&line, token) {
+            return false;
+        }
+        self.lines.push(line);
+        true
+    }
+
+    /// Changes the URL and attributes of the mapping for `token`, keeping the rest of its line.
+    ///
+    /// Like `insert`, this refuses changes that would turn the line into something else.
+    pub fn update(&mut self, token: &str, link: &Link) -> bool {
+        let index = match self.find(token) {
+            Some(index) => index,
+            None => return false,
+        };
+
+        let line = &self.lines[index];
+        let spans = field_spans(line);
+        let url_index = if token.is_empty() { 0 } else { 1 };
+        let url_end = spans[url_index].1;
+
+        // edits are collected as (start, end, replacement) and applied back to front
+        let mut edits = vec![(spans[url_index].0, url_end, link.url.clone())];
+        for (key, value) in link.attributes() {
+            let prefix = format!("{key}=");
+            let mut existing: Vec<_> = spans.iter().enumerate().skip(url_index + 1)
+                .filter(|(_, (start, end))| line[*start..*end].starts_with(&prefix))
+                .map(|(i, _)| i)
+                .collect();
+            // the last field is the one in effect, earlier ones are dropped
+            let last = existing.pop();
+            // remove fields together with the whitespace in front of them
+            let removal = |i: usize| (spans[i - 1].1, spans[i].1, String::new());
+            edits.extend(existing.into_iter().map(removal));
+
+            match (last, value) {
+                (Some(i), Some(value)) => edits.push((spans[i].0, spans[i].1, format!("{prefix}{value}"))),
+                (Some(i), None) => edits.push(removal(i)),
+                (None, Some(value)) => edits.push((url_end, url_end, format!(" {prefix}{value}"))),
+                (None, None) => (),
+            }
+        }
+
+        edits.sort_by_key(|&(start, end, _)| (start, end));
+        let mut line = line.clone();
+        for (start, end, replacement) in edits.into_iter().rev() {
+            line.replace_range(start..end, &replacement);
+        }
+        if !is_mapping_for(&line, token) {
+            return false;
+        }
+        self.lines[index] = line;
+        true
+    }
+
+    /// Removes all mappings for `token`.
+    pub fn remove(&mut self, token: &str) -> bool {
+        let before = self.lines.len();
+        self.lines.retain(|line| !is_mapping_for(line, token));
+        self.lines.len() != before
+    }
+
+    /// Writes the document to a temporary file next to `file_path` and moves it into place,
+    /// so readers never see a partially written file.
+    pub fn save(&self, file_path: impl AsRef<Path>) -> io::Result<()> {
+        let file_path = file_path.as_ref();
+        let mut tmp_path = file_path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut tmp_file = fs::File::create(&tmp_path)?;
+        tmp_file.write_all(self.to_string().as_bytes())?;
+        tmp_file.sync_all()?;
+        if let Ok(metadata) = fs::metadata(file_path) {
+            fs::set_permissions(&tmp_path, metadata.permissions())?;
+        }
+        fs::rename(&tmp_path, file_path)
+    }
+
+    /// Index of the line that defines the effective mapping for `token`.
+    fn find(&self, token: &str) -> Option<usize> {
+        self.lines.iter().rposition(|line| is_mapping_for(line, token))
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{line}")?;
+        }
+        if self.trailing_newline && !self.lines.is_empty() {
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Link {
+    /// All attributes as written to mapping files, `None` for unset ones.
+    fn attributes(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            ("redirect", self.redirect.map(|r| r.code().to_owned())),
+            ("forward", self.forward.then(|| "true".to_owned())),
+            ("default", self.default.clone()),
+            ("expires", self.expires.as_ref().map(|t| t.text.clone())),
+            ("not-before", self.not_before.as_ref().map(|t| t.text.clone())),
+        ]
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires.as_ref().is_some_and(|t| t.time <= now)
+    }
+
+    pub fn is_pending(&self, now: SystemTime) -> bool {
+        self.not_before.as_ref().is_some_and(|t| t.time > now)
+    }
+
+    /// Whether the link is only valid for some time, so clients should not remember it.
+    pub fn is_scheduled(&self) -> bool {
+        self.expires.is_some() || self.not_before.is_some()
+    }
+
+    /// Whether the URL contains `{1}`, `{2}`, ... or `{*}` placeholders for arguments.
+    pub fn is_template(&self) -> bool {
+        !placeholders(&self.url).is_empty()
+    }
+
+    /// The URL with its placeholders replaced by the percent-encoded `arguments`.
+    ///
+    /// `{*}` stands for all arguments separated by `/`.
+    /// If there are not enough arguments, the default URL is used, without one missing
+    /// arguments are left empty.
+    pub fn fill(&self, arguments: &[String]) -> String {
+        let placeholders = placeholders(&self.url);
+        let needed = placeholders.iter().map(|(_, _, index)| index.unwrap_or(1)).max().unwrap_or(0);
+        if let Some(default) = self.default.as_ref().filter(|_| arguments.len() < needed) {
+            return default.clone();
+        }
+
+        let mut url = String::new();
+        let mut last = 0;
+        for (start, end, index) in placeholders {
+            let value = match index {
+                Some(index) => arguments.get(index - 1).cloned().unwrap_or_default(),
+                None => arguments.join("/"),
+            };
+            url.push_str(&self.url[last..start]);
+            url.push_str(&percent_encode(&value, b""));
+            last = end;
+        }
+        url.push_str(&self.url[last..]);
+        url
+    }
 }
 
+impl Redirect {
+    pub fn from_code(code: &str) -> Option<Self> {
+        use Redirect::*;
+        match code {
+            "301" => Some(MovedPermanently),
+            "302" => Some(Found),
+            "303" => Some(SeeOther),
+            "307" => Some(Temporary),
+            "308" => Some(Permanent),
+            _ => None,
+        }
+    }
+
+    /// Whether clients may remember the redirect.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, Redirect::MovedPermanently | Redirect::Permanent)
+    }
+
+    pub fn code(&self) -> &'static str {
+        use Redirect::*;
+        match self {
+            MovedPermanently => "301",
+            Found => "302",
+            SeeOther => "303",
+            Temporary => "307",
+            Permanent => "308",
+        }
+    }
+}
 
-fn last_modified(file: &str) -> io::Result<SystemTime> {
+
+fn last_modified(file: impl AsRef<Path>) -> io::Result<SystemTime> {
     fs::metadata(file)?.modified()
 }
 
-fn read_mappings(file: &str) -> io::Result<HashMap<String, String>> {
-    let file_contents = fs::read_to_string(file)?;
-    let lines = file_contents.lines()
-        .filter(|l| !l.starts_with('#'))
-        .filter(|l| !l.is_empty());
-    let mut map = HashMap::new();
+/// Whether `token` can be written to a mapping file and read back the same.
+pub fn is_valid_token(token: &str) -> bool {
+    !token.is_empty() && !token.starts_with('#') && !token.contains(char::is_whitespace)
+}
 
-    for line in lines {
-        let columns: Vec<_> = line.split_whitespace().collect();
+/// Splits a mapping line into its token and the fields following it, starting with the URL.
+///
+/// Returns `None` for comments, empty lines and include directives.
+fn split_line(line: &str) -> Option<(&str, Vec<&str>)> {
+    if line.starts_with('#') || line.is_empty() || include_target(line).is_some() {
+        return None;
+    }
 
-        if line.starts_with(' ') && !columns.is_empty() {
-            map.insert("".to_owned(), columns[0].to_owned());
-        } else if columns.len() >= 2 {
-            map.insert(columns[0].to_owned(), columns[1].to_owned());
-        } else {
-            eprintln!("Invalid mapping '{line}'");
+    let mut columns: Vec<_> = line.split_whitespace().collect();
+    if line.starts_with(' ') {
+        Some(("", columns))
+    } else {
+        let token = columns.remove(0);
+        Some((token, columns))
+    }
+}
+
+/// The path or pattern of an `include <path>` line.
+///
+/// A link for the token `include` has a URL instead, so it keeps working.
+fn include_target(line: &str) -> Option<&str> {
+    let mut fields = line.strip_prefix("include")?.split_whitespace();
+    match (fields.next(), fields.next()) {
+        (Some(target), None) if line.starts_with("include ") || line.starts_with("include\t") => {
+            (!target.contains("://")).then_some(target)
+        },
+        _ => None,
+    }
+}
+
+fn is_glob(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name.to_string_lossy().contains(['*', '?']))
+}
+
+/// Files matching the `*` and `?` wildcards in the last component of `pattern`, sorted by name.
+fn expand_glob(pattern: &Path) -> Vec<PathBuf> {
+    let (Some(dir), Some(name)) = (pattern.parent(), pattern.file_name()) else {
+        return Vec::new();
+    };
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let name = name.to_string_lossy();
+
+    let mut paths: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .filter(|e| e.path().is_file())
+            .filter(|e| wildcard_match(&name, &e.file_name().to_string_lossy()))
+            .map(|e| pattern.with_file_name(e.file_name()))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<_>, Vec<_>) = (pattern.chars().collect(), name.chars().collect());
+    // the positions to continue from after the last `*`, for backtracking
+    let (mut p, mut n, mut star) = (0, 0, None);
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => { star = Some((p, n)); p += 1; },
+            Some(&c) if c == '?' || c == name[n] => { p += 1; n += 1; },
+            _ => match star {
+                Some((star_p, star_n)) => { p = star_p + 1; n = star_n + 1; star = Some((star_p, star_n + 1)); },
+                None => return false,
+            },
         }
     }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Byte ranges of the placeholders in `url` with their argument number, `None` for `{*}`.
+fn placeholders(url: &str) -> Vec<(usize, usize, Option<usize>)> {
+    let mut placeholders = Vec::new();
+    let mut offset = 0;
+
+    while let Some(start) = url[offset..].find('{').map(|i| offset + i) {
+        let Some(end) = url[start..].find('}').map(|i| start + i + 1) else {
+            break;
+        };
+        let index = match &url[start + 1..end - 1] {
+            "*" => None,
+            n => match n.parse() {
+                Ok(index) if index > 0 => Some(index),
+                // the brace might open a placeholder later on
+                _ => { offset = start + 1; continue; },
+            },
+        };
+        placeholders.push((start, end, index));
+        offset = end;
+    }
+    placeholders
+}
+
+/// Number of characters to insert, delete, replace or swap with their neighbour to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<_>, Vec<_>) = (a.chars().collect(), b.chars().collect());
+    // distances from the first i - 2, i - 1 and i characters of `a` to all prefixes of `b`
+    let mut before: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            current[j] = (previous[j] + 1).min(current[j - 1] + 1).min(previous[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before[j - 2] + 1);
+            }
+        }
+        before = std::mem::replace(&mut previous, current);
+    }
+    previous[b.len()]
+}
+
+fn is_mapping_for(line: &str, token: &str) -> bool {
+    matches!(split_line(line), Some((t, fields)) if t == token && !fields.is_empty())
+}
+
+/// Byte ranges of the whitespace separated fields in `line`.
+fn field_spans(line: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = None;
+
+    for (i, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => { spans.push((s, i)); start = None; },
+            (false, None) => start = Some(i),
+            _ => (),
+        }
+    }
+    if let Some(s) = start {
+        spans.push((s, line.len()));
+    }
+
+    spans
+}
+
+/// Builds a link from its URL and the fields following it.
+///
+/// Fields of the form `key=value` are attributes, all other fields are ignored.
+/// Attributes with invalid values are left unset and reported in the returned errors.
+pub fn parse_link(url: &str, fields: &[&str]) -> (Link, Vec<String>) {
+    let mut link = Link {
+        url: url.to_owned(),
+        redirect: None,
+        forward: false,
+        default: None,
+        expires: None,
+        not_before: None,
+    };
+    let mut errors = Vec::new();
+
+    for (key, value) in fields.iter().filter_map(|f| f.split_once('=')) {
+        match key {
+            "redirect" => {
+                link.redirect = Redirect::from_code(value);
+                if link.redirect.is_none() {
+                    errors.push(format!("Invalid redirect status '{value}'"));
+                }
+            },
+            "forward" => match value {
+                "true" => link.forward = true,
+                "false" => link.forward = false,
+                _ => errors.push(format!("Invalid forward value '{value}', expected true or false")),
+            },
+            "default" => link.default = Some(value.to_owned()),
+            "expires" | "not-before" => {
+                let timestamp = Timestamp::parse(value);
+                if timestamp.is_none() {
+                    errors.push(format!("Invalid time '{value}', expected e.g. 2024-05-17 or 2024-05-17T13:00"));
+                }
+                match key {
+                    "expires" => link.expires = timestamp,
+                    _ => link.not_before = timestamp,
+                }
+            },
+            _ => (),
+        }
+    }
+
+    (link, errors)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPPINGS: &str = "# personal links\n\
+        gh  https://github.com   redirect=307 owner=me   # code\n\
+        \n\
+        \x20https://example.com\n\
+        cb https://codeberg.org\n\
+        include more.txt\n";
+
+    fn link(url: &str, fields: &[&str]) -> Link {
+        parse_link(url, fields).0
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn keeps_unchanged_documents() {
+        assert_eq!(Document::parse(MAPPINGS).to_string(), MAPPINGS);
+        let without_newline = MAPPINGS.trim_end();
+        assert_eq!(Document::parse(without_newline).to_string(), without_newline);
+    }
+
+    #[test]
+    fn update_keeps_rest_of_line() {
+        let mut document = Document::parse(MAPPINGS);
+        assert!(document.update("gh", &link("https://github.com/me", &["redirect=307"])));
+        assert_eq!(document.to_string(), MAPPINGS.replace("https://github.com ", "https://github.com/me "));
+    }
+
+    #[test]
+    fn update_changes_attributes() {
+        let mut document = Document::parse(MAPPINGS);
+        assert!(document.update("gh", &link("https://github.com", &["forward=true"])));
+        assert!(document.update("cb", &link("https://codeberg.org", &["redirect=302", "expires=2024-06-01"])));
+        assert_eq!(document.to_string(), MAPPINGS
+            .replace("   redirect=307 owner=me", " forward=true owner=me")
+            .replace("codeberg.org\n", "codeberg.org redirect=302 expires=2024-06-01\n"));
+
+        // the last of repeated attributes is in effect, so it is changed and the others dropped
+        let mut document = Document::parse("gh https://a.example redirect=301 forward=true redirect=302\n");
+        assert!(document.update("gh", &link("https://a.example", &["redirect=307", "forward=true"])));
+        assert_eq!(document.to_string(), "gh https://a.example forward=true redirect=307\n");
+        assert!(document.update("gh", &link("https://a.example", &[])));
+        assert_eq!(document.to_string(), "gh https://a.example\n");
+    }
+
+    #[test]
+    fn update_root_link() {
+        let mut document = Document::parse(" https://example.com other\n");
+        assert!(document.update("", &link("https://example.org", &[])));
+        assert_eq!(document.to_string(), " https://example.org other\n");
+        assert!(!document.update("missing", &link("https://example.org", &[])));
+    }
+
+    #[test]
+    fn insert_appends_line() {
+        let mut document = Document::parse(MAPPINGS);
+        assert!(!document.insert("gh", &link("https://gitlab.com", &[])));
+        assert!(document.insert("gl", &link("https://gitlab.com", &["redirect=302"])));
+        assert_eq!(document.to_string(), format!("{MAPPINGS}gl https://gitlab.com redirect=302\n"));
+    }
+
+    #[test]
+    fn refuses_lines_read_as_includes() {
+        let mut document = Document::parse(MAPPINGS);
+        assert!(!document.insert("include", &link("foo", &[])));
+        assert!(document.insert("include", &link("https://include.example", &[])));
+        assert!(!document.update("include", &link("foo", &[])));
+        assert_eq!(document.to_string(), format!("{MAPPINGS}include https://include.example\n"));
+    }
+
+    #[test]
+    fn remove_keeps_other_lines() {
+        let mut document = Document::parse(MAPPINGS);
+        assert!(document.remove("gh"));
+        assert!(!document.remove("gh"));
+        assert!(!document.remove("include"));
+        assert_eq!(document.to_string(), MAPPINGS.replace("gh  https://github.com   redirect=307 owner=me   # code\n", ""));
+    }
+
+    #[test]
+    fn fills_placeholders() {
+        let wiki = link("https://en.wikipedia.org/wiki/{1}", &[]);
+        assert!(wiki.is_template());
+        assert_eq!(wiki.fill(&args(&["Rust"])), "https://en.wikipedia.org/wiki/Rust");
+        assert_eq!(wiki.fill(&args(&["a b/c?d#e", "ignored"])), "https://en.wikipedia.org/wiki/a%20b%2Fc%3Fd%23e");
+
+        let search = link("https://s.example/?q={*}", &[]);
+        assert_eq!(search.fill(&args(&["rust", "lang"])), "https://s.example/?q=rust%2Flang");
+        assert_eq!(link("https://e.example/{2}/{1}", &[]).fill(&args(&["x", "y"])), "https://e.example/y/x");
+    }
+
+    #[test]
+    fn fills_missing_arguments() {
+        let pair = link("https://e.example/{1}/{2}", &[]);
+        assert_eq!(pair.fill(&args(&["x"])), "https://e.example/x/");
+        assert_eq!(link("https://s.example/?q={*}", &[]).fill(&[]), "https://s.example/?q=");
+
+        let with_default = link("https://e.example/{1}/{2}", &["default=https://e.example"]);
+        assert_eq!(with_default.fill(&args(&["x"])), "https://e.example");
+        assert_eq!(with_default.fill(&args(&["x", "y"])), "https://e.example/x/y");
+        let all_with_default = link("https://s.example/?q={*}", &["default=https://s.example"]);
+        assert_eq!(all_with_default.fill(&[]), "https://s.example");
+    }
+
+    #[test]
+    fn keeps_literal_braces() {
+        assert!(!link("https://e.example/{a}/{0}/{", &[]).is_template());
+        assert_eq!(link("https://e.example/{a}/{1}", &[]).fill(&args(&["x"])), "https://e.example/{a}/x");
+        assert_eq!(link("https://e.example/{{1}}", &[]).fill(&args(&["x"])), "https://e.example/{x}");
+        assert_eq!(link("https://e.example/{1", &[]).fill(&args(&["x"])), "https://e.example/{1");
+    }
 
-    Ok(map)
 }
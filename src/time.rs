@@ -0,0 +1,176 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                            "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+/// Years accepted by `Timestamp::parse`, which keeps the calendar math from overflowing.
+const YEARS: std::ops::RangeInclusive<i64> = 1970..=9999;
+
+
+/// A point in time broken down into its UTC calendar fields.
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+/// A point in time given by a human, which is kept as written.
+#[derive(Clone)]
+pub struct Timestamp {
+    pub text: String,
+    pub time: SystemTime,
+}
+
+
+impl DateTime {
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let secs = match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+        let (year, month, day) = civil_from_days(days);
+
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (secs_of_day / 3600) as u32,
+            minute: (secs_of_day / 60 % 60) as u32,
+            second: (secs_of_day % 60) as u32,
+        }
+    }
+
+    /// Formats like `2024-05-17T13:02:09Z`.
+    pub fn rfc3339(&self) -> String {
+        format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+
+    /// Formats like `17/May/2024:13:02:09 +0000`, as used by the Common Log Format.
+    pub fn clf(&self) -> String {
+        format!("{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000",
+            self.day, MONTHS[self.month as usize - 1], self.year, self.hour, self.minute, self.second)
+    }
+}
+
+impl Timestamp {
+    /// Parses `2024-05-17`, `2024-05-17T13:02` or `2024-05-17T13:02:09` in UTC, optionally followed by `Z`.
+    ///
+    /// A date without a time means the start of that day.
+    pub fn parse(text: &str) -> Option<Self> {
+        let utc = text.strip_suffix('Z').unwrap_or(text);
+        let (date, time) = match utc.split_once('T') {
+            Some((date, time)) => (date, Some(time)),
+            None => (utc, None),
+        };
+
+        let date: Vec<_> = date.split('-').collect();
+        let [year, month, day] = date[..] else {
+            return None;
+        };
+        let (year, month, day) = (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+        if !YEARS.contains(&year) {
+            return None;
+        }
+        let days = days_from_civil(year, month, day);
+        if civil_from_days(days) != (year, month, day) {
+            return None;
+        }
+
+        let secs_of_day = match time {
+            Some(time) => {
+                let time: Vec<u32> = time.split(':').map(|t| t.parse().ok()).collect::<Option<_>>()?;
+                let (hour, minute, second) = match time[..] {
+                    [hour, minute] => (hour, minute, 0),
+                    [hour, minute, second] => (hour, minute, second),
+                    _ => return None,
+                };
+                if hour > 23 || minute > 59 || second > 59 {
+                    return None;
+                }
+                hour * 3600 + minute * 60 + second
+            },
+            None => 0,
+        };
+
+        let secs = u64::try_from(days.checked_mul(86400)?.checked_add(secs_of_day as i64)?).ok()?;
+        Some(Timestamp { text: text.to_owned(), time: UNIX_EPOCH + Duration::from_secs(secs) })
+    }
+}
+
+
+/// Converts year, month and day to days since the unix epoch.
+///
+/// See <https://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Converts days since the unix epoch to year, month and day.
+///
+/// See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(text: &str) -> Option<u64> {
+        Timestamp::parse(text).map(|t| t.time.duration_since(UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(secs("1970-01-01"), Some(0));
+        assert_eq!(secs("2024-05-17"), Some(1715904000));
+        assert_eq!(secs("2024-05-17Z"), Some(1715904000));
+        assert_eq!(secs("2024-05-17T13:02"), Some(1715904000 + 13 * 3600 + 2 * 60));
+        assert_eq!(secs("2024-05-17T13:02:09"), Some(1715904000 + 13 * 3600 + 2 * 60 + 9));
+        assert_eq!(secs("2024-05-17T13:02:09Z"), Some(1715904000 + 13 * 3600 + 2 * 60 + 9));
+        assert_eq!(secs("2024-02-29"), Some(1709164800));
+    }
+
+    #[test]
+    fn keeps_text() {
+        assert_eq!(Timestamp::parse("2024-05-17Z").map(|t| t.text).as_deref(), Some("2024-05-17Z"));
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        for text in ["", "2024", "2024-05", "2024-05-17-01", "2024-13-01", "2024-02-30", "2023-02-29",
+                "2024-05-17T", "2024-05-17T13", "2024-05-17T24:00", "2024-05-17T13:60", "2024-05-17T13:02:60",
+                "2024-05-17T13:02:09:00", "2024-05-17ZZ", "1969-12-31", "17.05.2024", "10000-01-01",
+                "9000000000000000000-01-01", "2024-99999999999-01", "2024-01-99999999999"] {
+            assert!(Timestamp::parse(text).is_none(), "{text}");
+        }
+    }
+
+    #[test]
+    fn formats_date_time() {
+        let time = DateTime::from_system_time(UNIX_EPOCH + Duration::from_secs(1715950929));
+        assert_eq!(time.rfc3339(), "2024-05-17T13:02:09Z");
+        assert_eq!(time.clf(), "17/May/2024:13:02:09 +0000");
+    }
+}
@@ -0,0 +1,170 @@
+use std::fs;
+use std::io;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+
+pub type Stream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Wraps accepted connections in TLS, picking the certificate by SNI.
+#[derive(Clone)]
+pub struct Acceptor {
+    config: Arc<ServerConfig>,
+    resolver: Arc<Resolver>,
+}
+
+/// Where to find a certificate chain and its key.
+///
+/// Certificates without a server name are used for clients that do not send SNI
+/// or ask for an unknown name.
+#[derive(Debug)]
+pub struct CertSource {
+    pub server_name: Option<String>,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[derive(Debug)]
+struct Resolver {
+    certs: RwLock<Vec<LoadedCert>>,
+}
+
+#[derive(Debug)]
+struct LoadedCert {
+    source: CertSource,
+    key: Arc<CertifiedKey>,
+    last_modified: (SystemTime, SystemTime),
+}
+
+
+impl Acceptor {
+    pub fn new(sources: Vec<CertSource>) -> io::Result<Self> {
+        let certs = sources.into_iter()
+            .map(LoadedCert::load)
+            .collect::<io::Result<Vec<_>>>()?;
+        let resolver = Arc::new(Resolver { certs: RwLock::new(certs) });
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::clone(&resolver) as Arc<dyn ResolvesServerCert>);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Acceptor { config: Arc::new(config), resolver })
+    }
+
+    /// The handshake happens on the first read or write, so it does not block the caller.
+    pub fn accept(&self, stream: TcpStream) -> io::Result<Stream> {
+        let connection = ServerConnection::new(Arc::clone(&self.config)).map_err(tls_error)?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+
+    /// Certificate and key files, to be watched for renewals.
+    pub fn watched_paths(&self) -> Vec<PathBuf> {
+        let certs = match self.resolver.certs.read() {
+            Ok(certs) => certs,
+            Err(_) => return Vec::new(),
+        };
+        certs.iter()
+            .flat_map(|c| [PathBuf::from(&c.source.cert_path), PathBuf::from(&c.source.key_path)])
+            .collect()
+    }
+
+    /// Loads certificates again whose files have been modified.
+    ///
+    /// If loading fails, the previous certificate stays in use.
+    pub fn reload_if_changed(&self) {
+        let changed = match self.resolver.certs.read() {
+            Ok(certs) => certs.iter().any(|c| c.is_outdated()),
+            Err(_) => return,
+        };
+        if !changed {
+            return;
+        }
+
+        let mut certs = match self.resolver.certs.write() {
+            Ok(certs) => certs,
+            Err(_) => return,
+        };
+
+        for cert in certs.iter_mut().filter(|c| c.is_outdated()) {
+
+            match cert.source.certified_key() {
+                Ok(key) => {
+                    cert.key = Arc::new(key);
+                    println!("Reloaded certificate {}", cert.source.cert_path);
+                },
+                Err(e) => eprintln!("Unable to reload certificate {}: {e}", cert.source.cert_path),
+            }
+            // don't retry a broken file until it changes again
+            if let Ok(last_modified) = cert.source.last_modified() {
+                cert.last_modified = last_modified;
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().ok()?;
+        let server_name = client_hello.server_name();
+
+        certs.iter()
+            .find(|c| c.source.server_name.is_some() && c.source.server_name.as_deref() == server_name)
+            .or_else(|| certs.iter().find(|c| c.source.server_name.is_none()))
+            .or_else(|| certs.first())
+            .map(|c| Arc::clone(&c.key))
+    }
+}
+
+impl LoadedCert {
+    fn load(source: CertSource) -> io::Result<Self> {
+        Ok(LoadedCert {
+            last_modified: source.last_modified()?,
+            key: Arc::new(source.certified_key()?),
+            source,
+        })
+    }
+
+    fn is_outdated(&self) -> bool {
+        matches!(self.source.last_modified(), Ok(last_modified) if last_modified != self.last_modified)
+    }
+}
+
+impl CertSource {
+    fn certified_key(&self) -> io::Result<CertifiedKey> {
+        let chain = CertificateDer::pem_file_iter(&self.cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| pem_error(&self.cert_path, e))?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path)
+            .map_err(|e| pem_error(&self.key_path, e))?;
+
+        let signing_key = ring::default_provider().key_provider
+            .load_private_key(key)
+            .map_err(tls_error)?;
+        Ok(CertifiedKey::new(chain, signing_key))
+    }
+
+    fn last_modified(&self) -> io::Result<(SystemTime, SystemTime)> {
+        Ok((fs::metadata(&self.cert_path)?.modified()?, fs::metadata(&self.key_path)?.modified()?))
+    }
+}
+
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn pem_error(path: &str, e: rustls::pki_types::pem::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{path}: {e:?}"))
+}
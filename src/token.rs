@@ -0,0 +1,141 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::server;
+use crate::store::Store;
+
+
+pub struct Generator {
+    pub length: usize,
+    pub alphabet: Alphabet,
+    pub strategy: Strategy,
+}
+
+#[derive(Clone, Copy)]
+pub enum Alphabet {
+    Base62,
+    Lowercase,
+    Friendly,
+}
+
+#[derive(Clone, Copy)]
+pub enum Strategy {
+    Random,
+    Sequential,
+    Hash,
+}
+
+
+const ATTEMPTS_PER_LENGTH: u64 = 16;
+const MAX_LENGTH: usize = 32;
+
+
+impl Generator {
+    /// Finds a token for `url` that is neither used in `store` nor reserved by the server.
+    ///
+    /// With the hash strategy the same URL always yields the same token,
+    /// so a token already pointing to `url` is returned as well.
+    pub fn generate(&self, store: &Store, url: &str) -> Option<String> {
+        if let Strategy::Sequential = self.strategy {
+            return self.next_sequential(store);
+        }
+
+        let base_seed = match self.strategy {
+            Strategy::Hash => fnv1a(url.as_bytes()),
+            _ => RandomState::new().build_hasher().finish(),
+        };
+
+        for length in self.length.max(1)..=MAX_LENGTH {
+            for attempt in 0..ATTEMPTS_PER_LENGTH {
+                let seed = splitmix(base_seed ^ ((length as u64) << 32) ^ attempt);
+                let token = self.encode(seed, length);
+                match store.get(&token) {
+                    Some(link) if matches!(self.strategy, Strategy::Hash) && link.url == url => return Some(token),
+                    None if !server::is_reserved(&token) => return Some(token),
+                    _ => (),
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Returns the first free token when counting up from the shortest one.
+    fn next_sequential(&self, store: &Store) -> Option<String> {
+        let chars = self.alphabet.chars();
+        let base = chars.len() as u64;
+
+        for n in 0..=(store.len() as u64 + 1) {
+            let mut digits = Vec::new();
+            let mut rest = n;
+            loop {
+                digits.push(chars[(rest % base) as usize]);
+                rest /= base;
+                if rest == 0 {
+                    break;
+                }
+            }
+            while digits.len() < self.length {
+                digits.push(chars[0]);
+            }
+
+            let token: String = digits.iter().rev().map(|&c| c as char).collect();
+            if store.get(&token).is_none() && !server::is_reserved(&token) {
+                return Some(token);
+            }
+        }
+
+        None
+    }
+
+    fn encode(&self, seed: u64, length: usize) -> String {
+        let chars = self.alphabet.chars();
+        (0..length as u64)
+            .map(|i| chars[(splitmix(seed.wrapping_add(i)) % chars.len() as u64) as usize] as char)
+            .collect()
+    }
+}
+
+impl Alphabet {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "base62" => Some(Alphabet::Base62),
+            "lowercase" => Some(Alphabet::Lowercase),
+            "friendly" => Some(Alphabet::Friendly),
+            _ => None,
+        }
+    }
+
+    fn chars(&self) -> &'static [u8] {
+        match self {
+            Alphabet::Base62 => b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz",
+            Alphabet::Lowercase => b"0123456789abcdefghijklmnopqrstuvwxyz",
+            // no 0/o, 1/i/l
+            Alphabet::Friendly => b"23456789abcdefghjkmnpqrstuvwxyz",
+        }
+    }
+}
+
+impl Strategy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "random" => Some(Strategy::Random),
+            "sequential" => Some(Strategy::Sequential),
+            "hash" => Some(Strategy::Hash),
+            _ => None,
+        }
+    }
+}
+
+
+/// Stable across builds and platforms, unlike the hashers in std.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+fn splitmix(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,214 @@
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+
+/// Quiet period after the last change before `on_change` is called, so a save that
+/// consists of several writes or a rename is only handled once.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+/// Used where files can not be watched.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+
+/// Calls `on_change` from a background thread whenever one of the files returned by `paths`
+/// has been changed, created, removed or replaced.
+///
+/// `paths` is asked again after every change, so the set of files may change over time.
+/// Directories in it are watched for changes to any of their entries.
+pub fn spawn<P, F>(paths: P, on_change: F)
+where
+    P: Fn() -> Vec<PathBuf> + Send + 'static,
+    F: Fn() + Send + 'static,
+{
+    thread::spawn(move || {
+        #[cfg(target_os = "linux")]
+        {
+            let e = match inotify::Inotify::new() {
+                Ok(inotify) => inotify.run(&paths, &on_change),
+                Err(e) => e,
+            };
+            eprintln!("Unable to watch files, checking every {} seconds instead: {e}", POLL_INTERVAL.as_secs());
+        }
+
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            on_change();
+        }
+    });
+}
+
+
+/// Makes relative paths like `mappings.txt` have a parent, so the directory can be watched.
+fn normalize(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => path.to_owned(),
+        _ => Path::new(".").join(path),
+    }
+}
+
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io;
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+    use std::os::raw::{c_char, c_int, c_short, c_ulong};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    use super::{normalize, DEBOUNCE};
+
+
+    pub struct Inotify {
+        file: File,
+        fd: c_int,
+    }
+
+    #[repr(C)]
+    struct PollFd {
+        fd: c_int,
+        events: c_short,
+        revents: c_short,
+    }
+
+
+    extern "C" {
+        fn inotify_init1(flags: c_int) -> c_int;
+        fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int;
+        fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+    }
+
+
+    const IN_NONBLOCK: c_int = 0o4000;
+    const IN_CLOEXEC: c_int = 0o2000000;
+    const IN_MODIFY: u32 = 0x2;
+    const IN_CLOSE_WRITE: u32 = 0x8;
+    const IN_MOVED_FROM: u32 = 0x40;
+    const IN_MOVED_TO: u32 = 0x80;
+    const IN_CREATE: u32 = 0x100;
+    const IN_DELETE: u32 = 0x200;
+    const IN_Q_OVERFLOW: u32 = 0x4000;
+    const IN_IGNORED: u32 = 0x8000;
+    /// Editors that save to a temporary file and rename it show up as moves.
+    const WATCH_MASK: u32 = IN_MODIFY | IN_CLOSE_WRITE | IN_MOVED_FROM | IN_MOVED_TO | IN_CREATE | IN_DELETE;
+    const EVENT_HEADER_SIZE: usize = 16;
+    const POLLIN: c_short = 1;
+
+
+    impl Inotify {
+        pub fn new() -> io::Result<Self> {
+            let fd = unsafe { inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // the file closes the descriptor once dropped
+            let file = unsafe { File::from_raw_fd(fd) };
+            Ok(Inotify { file, fd })
+        }
+
+        /// Watches the directories containing `paths` until an error occurs.
+        ///
+        /// Watching directories instead of the files themselves keeps working when a file is
+        /// replaced, which would otherwise leave the watch on the old, deleted file.
+        pub fn run(mut self, paths: &dyn Fn() -> Vec<PathBuf>, on_change: &dyn Fn()) -> io::Error {
+            let mut dirs = HashMap::new();
+
+            loop {
+                let watched: Vec<_> = paths().iter().map(|p| normalize(p)).collect();
+                for path in &watched {
+                    let dir = if path.is_dir() { path.clone() } else { path.parent().unwrap_or(path).to_owned() };
+                    match self.add_watch(&dir) {
+                        Ok(wd) => { dirs.insert(wd, dir); },
+                        Err(e) => return e,
+                    }
+                }
+
+                // wait for a relevant event, then until things calm down
+                let result = (|| {
+                    loop {
+                        self.wait(None)?;
+                        if self.read_events(&mut dirs, &watched)? {
+                            break;
+                        }
+                    }
+                    while self.wait(Some(DEBOUNCE))? {
+                        self.read_events(&mut dirs, &watched)?;
+                    }
+                    Ok(())
+                })();
+                if let Err(e) = result {
+                    return e;
+                }
+
+                on_change();
+            }
+        }
+
+        fn add_watch(&self, dir: &Path) -> io::Result<c_int> {
+            let path = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            match unsafe { inotify_add_watch(self.fd, path.as_ptr(), WATCH_MASK) } {
+                wd if wd < 0 => Err(io::Error::last_os_error()),
+                wd => Ok(wd),
+            }
+        }
+
+        /// Waits for events, returning `false` if there were none within `timeout`.
+        fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+            let mut poll_fd = PollFd { fd: self.fd, events: POLLIN, revents: 0 };
+            let timeout = timeout.map(|t| t.as_millis() as c_int).unwrap_or(-1);
+
+            match unsafe { poll(&mut poll_fd, 1, timeout) } {
+                n if n < 0 => {
+                    let e = io::Error::last_os_error();
+                    // signals interrupt the wait, which is harmless
+                    if e.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(e) }
+                },
+                n => Ok(n > 0),
+            }
+        }
+
+        /// Reads all pending events, returning whether any of them concerns `watched`.
+        fn read_events(&mut self, dirs: &mut HashMap<c_int, PathBuf>, watched: &[PathBuf]) -> io::Result<bool> {
+            let mut buffer = [0u8; 4096];
+            let mut relevant = false;
+
+            loop {
+                let len = match self.file.read(&mut buffer) {
+                    Ok(len) => len,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(relevant),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+
+                let mut offset = 0;
+                while offset + EVENT_HEADER_SIZE <= len {
+                    let field = |i: usize| {
+                        let start = offset + 4 * i;
+                        u32::from_ne_bytes([buffer[start], buffer[start + 1], buffer[start + 2], buffer[start + 3]])
+                    };
+                    let (wd, mask, name_len) = (field(0) as c_int, field(1), field(3) as usize);
+                    let name_start = offset + EVENT_HEADER_SIZE;
+                    let name = &buffer[name_start..(name_start + name_len).min(len)];
+                    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                    offset = name_start + name_len;
+
+                    if mask & IN_Q_OVERFLOW != 0 {
+                        relevant = true;
+                    } else if mask & IN_IGNORED != 0 {
+                        // the directory is gone, it is watched again if it comes back
+                        dirs.remove(&wd);
+                        relevant = true;
+                    } else if let Some(dir) = dirs.get(&wd) {
+                        let path = dir.join(std::ffi::OsStr::from_bytes(name));
+                        relevant |= watched.iter().any(|w| *w == path || w == dir);
+                    }
+                }
+            }
+        }
+    }
+}